@@ -0,0 +1,42 @@
+#![feature(test)]
+
+extern crate bifrost;
+extern crate test;
+
+use bifrost::vector_clock::{Relation, StandardVectorClock};
+use test::Bencher;
+
+// the clocks disagree in opposite directions on their first two servers
+fn concurrent_pair(size: u64) -> (StandardVectorClock, StandardVectorClock) {
+    let a = (0..size).map(|server| (server, if server == 1 { 1 } else { 2 }));
+    let b = (0..size).map(|server| (server, if server == 0 { 1 } else { 2 }));
+    (
+        StandardVectorClock::from_pairs_checked(a.collect()).unwrap(),
+        StandardVectorClock::from_pairs_checked(b.collect()).unwrap(),
+    )
+}
+
+#[bench]
+fn relation_concurrent_early(b: &mut Bencher) {
+    let (x, y) = concurrent_pair(10_000);
+    b.iter(|| assert_eq!(x.relation(&y), Relation::Concurrent));
+}
+
+#[bench]
+fn relation_equal_full_scan(b: &mut Bencher) {
+    let (x, _) = concurrent_pair(10_000);
+    let y = x.clone();
+    b.iter(|| assert_eq!(x.relation(&y), Relation::Equal));
+}
+
+#[bench]
+fn inc_cloned(b: &mut Bencher) {
+    let (mut x, _) = concurrent_pair(1_000);
+    b.iter(|| x.inc(0));
+}
+
+#[bench]
+fn inc_in_place(b: &mut Bencher) {
+    let (mut x, _) = concurrent_pair(1_000);
+    b.iter(|| x.inc_in_place(0));
+}
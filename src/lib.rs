@@ -40,6 +40,7 @@ extern crate tokio_service;
 extern crate tokio_timer;
 
 extern crate bincode;
+extern crate erased_serde;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
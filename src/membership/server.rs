@@ -47,13 +47,16 @@ impl Service for HeartbeatService {
 impl HeartbeatService {
     fn update_raft(&self, online: &Vec<u64>, offline: &Vec<u64>) {
         let log = commands::hb_online_changed::new(online, offline);
-        let (fn_id, _, data) = log.encode();
+        let (fn_id, _, data) = log.encode(&*self.raft_service.codec);
         self.raft_service.c_command(LogEntry {
             id: 0,
             term: 0,
             sm_id: DEFAULT_SERVICE_ID,
             fn_id,
             data,
+            trace_context: None,
+            fence_term: None,
+            idempotency_token: None,
         });
     }
     fn transfer_leadership(&self) {
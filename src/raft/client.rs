@@ -1,37 +1,241 @@
 use super::*;
 use bifrost_hasher::{hash_bytes, hash_str};
 use futures::prelude::{async, await};
+use futures::sync::oneshot;
 use raft::state_machine::callback::client::SubscriptionService;
 use raft::state_machine::callback::SubKey;
 use raft::state_machine::configs::commands::{
-    subscribe as conf_subscribe, unsubscribe as conf_unsubscribe,
+    on_leader_elected, subscribe as conf_subscribe, unsubscribe as conf_unsubscribe,
 };
 use raft::state_machine::configs::CONFIG_SM_ID;
-use raft::state_machine::master::{ExecError, ExecResult};
+use raft::state_machine::master::{ExecError, ExecResult, RetryReason};
 use raft::state_machine::OpType;
 use raft::{AsyncServiceClient, ClientCmdResponse, ClientQryResponse, LogEntry, RaftMsg};
+pub use raft::codec::{BincodeCodec, Codec};
 use rand;
 use rpc;
 use std::clone::Clone;
-use std::cmp::max;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
 use std::iter::FromIterator;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use utils::async_locks::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_timer::{self, Timer};
+use utils::async_locks::{Mutex, RwLock};
+use utils::fut_exec::{exec, spawn};
+use utils::time::{duration_to_ms, get_time};
 
 const ORDERING: Ordering = Ordering::Relaxed;
+const READ_INDEX_RETRY: usize = 50;
+const READ_INDEX_WAIT_MS: u64 = 20;
+const QUORUM_READ_RETRY: usize = 5;
+const COMMIT_POLL_MS: u64 = 20;
+// sleeps and timeouts are rounded up to the tick, the longest one is TIMER_SLOTS ticks (~11 min)
+const TIMER_TICK_MS: u64 = 10;
+const TIMER_SLOTS: usize = 65536;
 pub type Client = Arc<AsyncServiceClient>;
 pub type SubscriptionReceipt = (SubKey, u64);
+pub type CommitWatcher = Box<Fn(u64) + Send + Sync>;
 
 lazy_static! {
     pub static ref CALLBACK: RwLock<Option<Arc<SubscriptionService>>> = RwLock::new(None);
+    // one timer thread for the sleeps and timeouts of every client
+    static ref TIMER: Timer = tokio_timer::wheel()
+        .tick_duration(Duration::from_millis(TIMER_TICK_MS))
+        .num_slots(TIMER_SLOTS)
+        .build();
+}
+
+/// How up to date the member answering a query has to be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadConsistency {
+    /// Any member answers with whatever state it has
+    Stale,
+    /// Any member that has seen at least the last log this client has seen
+    ReadYourWrites,
+    /// Only the leader answers
+    Linearizable,
+}
+
+/// Attributes of one `execute` call, reported to the tracer once the call completes
+#[derive(Debug, Clone)]
+pub struct CallSpan {
+    pub sm_id: u64,
+    pub fn_id: u64,
+    pub op: OpType,
+    pub leader_id: u64,
+    pub retries: usize,
+    pub succeeded: bool,
+}
+
+/// Hook for bridging client calls into a distributed tracing setup.
+/// The context returned by `start_span` travels to the servers in `LogEntry::trace_context`.
+pub trait Tracer: Send + Sync {
+    fn start_span(&self, sm_id: u64, fn_id: u64, op: OpType) -> Option<Vec<u8>>;
+    fn end_span(&self, span: CallSpan);
+}
+
+pub struct NoopTracer;
+
+impl Tracer for NoopTracer {
+    fn start_span(&self, _sm_id: u64, _fn_id: u64, _op: OpType) -> Option<Vec<u8>> {
+        None
+    }
+    fn end_span(&self, _span: CallSpan) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallOutcome {
+    Success, // on the first attempt
+    Retried, // succeeded after retries
+    Failed,
+}
+
+/// Receives client side measurements, all callbacks default to doing nothing
+pub trait Metrics: Send + Sync {
+    /// Wall time of a whole `execute` call, retries included
+    fn record_latency(&self, _op: OpType, _outcome: CallOutcome, _duration: Duration) {}
+}
+
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// A command submitted by this client, see `RaftClient::recent_commands`
+#[derive(Debug, Clone)]
+pub struct CommandRecord {
+    pub sm_id: u64,
+    pub fn_id: u64,
+    pub token: u64, // idempotency token the command was sent with
+    pub outcome: CallOutcome,
+    pub timestamp: i64,
+}
+
+/// How far behind this client the member answering a query was, see `RaftClient::query_detailed`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Staleness {
+    pub member_id: u64,
+    pub member_log_id: u64, // last log id of the member when it answered
+    pub watermark: u64,     // last log id seen by the client when the query was sent
+}
+
+impl Staleness {
+    /// Log entries the answer is missing, 0 when the member was not behind
+    pub fn gap(&self) -> u64 {
+        self.watermark.saturating_sub(self.member_log_id)
+    }
+}
+
+// result of a call and how many times it was retried
+type Attempted = (ExecResult, usize);
+
+// (sm id, fn id, request hash, read consistency)
+type QueryKey = (u64, u64, u64, u8);
+
+// (sm id, fn id, request hash)
+type CacheKey = (u64, u64, u64);
+
+#[derive(Clone)]
+pub struct RaftClientConfig {
+    /// Re-discover the leader and send the command again if it has not responded in time.
+    /// The first response wins. Both copies carry the same idempotency token,
+    /// the state machines apply whichever reaches the log first and skip the other.
+    pub hedge_after: Option<Duration>,
+    /// Addresses of the members most likely to win an election, highest priority first.
+    /// When the leader fails these are tried before the others.
+    pub leader_priority: Vec<String>,
+    /// Encodes arguments, results and subscription messages, servers have to use the same codec.
+    pub codec: Arc<Codec>,
+    /// Consecutive request failures before a member is blacklisted, 0 disables the blacklist.
+    /// Blacklisted members are left out of leader and member selection while any other is left.
+    pub blacklist_after: u32,
+    /// How often blacklisted members are probed for recovery
+    pub probe_interval: Duration,
+    /// Keep a connection to every known member so a failover does not wait for a connect.
+    /// Every `probe_interval` connected members are probed, the connections of those not
+    /// answering are replaced and missing ones are established.
+    pub keep_all_connected: bool,
+    pub tracer: Arc<Tracer>,
+    pub metrics: Arc<Metrics>,
+    /// Number of recently submitted commands to remember, 0 disables the log
+    pub command_log_size: usize,
+    /// Skip members that do not accept a connection in time during discovery,
+    /// they are tried again on the next refresh. No bound when `None`.
+    pub connect_timeout: Option<Duration>,
+    /// Identical queries issued while one is in flight wait for it and share its result
+    /// instead of sending their own
+    pub coalesce_queries: bool,
+    /// Largest encoded request accepted, bigger ones fail with `ExecError::PayloadTooLarge`
+    /// before anything is sent. No limit when `None`.
+    pub max_payload_size: Option<usize>,
+    /// Probe the leader this often and look for a new one after `leader_probe_failures`
+    /// probes in a row fail, instead of waiting for a command to fail. Off when `None`.
+    pub leader_probe_interval: Option<Duration>,
+    pub leader_probe_failures: u32,
+    /// Wait before retrying a failed command or a query that hit a lagging member,
+    /// doubling from `base_backoff` on every retry up to `max_backoff`, with random jitter.
+    /// The first attempt is never delayed, a zero `base_backoff` retries right away.
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Send a query to the leader after this many members in a row were behind the client,
+    /// instead of trying the other members. Never when `None`.
+    pub left_behind_escalation: Option<usize>,
+    /// Longest wait for the answer to any single RPC, discovery and member probes included.
+    /// A command timing out is retried like one that failed. A query timing out is retried
+    /// on the other members, except a linearizable one, which only the leader answers.
+    /// No bound when `None`. Timeouts run on a shared timer with a 10ms tick,
+    /// up to about 11 minutes, this one and `operation_timeout` alike.
+    pub rpc_timeout: Option<Duration>,
+    /// Longest an `execute` call may take with all of its retries,
+    /// it fails with `ExecError::Timeout` after that. No bound when `None`.
+    pub operation_timeout: Option<Duration>,
+    /// At most this many calls in flight at once, every kind of call counts.
+    /// No limit when `None`.
+    pub max_in_flight: Option<usize>,
+    /// Calls over `max_in_flight` wait for a slot while fewer than this many are waiting,
+    /// the others fail with `ExecError::Overloaded`. Calls fail right away when 0.
+    pub max_queued: usize,
+    /// Results of `query_optimistic` to keep, the oldest are evicted first. 0 disables the cache.
+    pub query_cache_size: usize,
+}
+
+impl Default for RaftClientConfig {
+    fn default() -> RaftClientConfig {
+        RaftClientConfig {
+            hedge_after: None,
+            leader_priority: Vec::new(),
+            codec: Arc::new(BincodeCodec),
+            blacklist_after: 0,
+            probe_interval: Duration::from_secs(1),
+            keep_all_connected: false,
+            tracer: Arc::new(NoopTracer),
+            metrics: Arc::new(NoopMetrics),
+            command_log_size: 0,
+            connect_timeout: None,
+            coalesce_queries: false,
+            max_payload_size: None,
+            leader_probe_interval: None,
+            leader_probe_failures: 3,
+            base_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(500),
+            left_behind_escalation: None,
+            rpc_timeout: None,
+            operation_timeout: None,
+            max_in_flight: None,
+            max_queued: 0,
+            query_cache_size: 1024,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ClientError {
     LeaderIdValid,
     ServerUnreachable,
+    DiscoveryPaused,
 }
 
 #[derive(Debug)]
@@ -41,6 +245,14 @@ pub enum SubscriptionError {
     CannotFindSubId,
 }
 
+#[derive(Debug, Clone)]
+pub struct ConnectionStat {
+    pub member_id: u64,
+    pub address: String,
+    pub requests_sent: u64,
+    pub last_used: i64,
+}
+
 struct QryMeta {
     pos: AtomicU64,
 }
@@ -50,6 +262,12 @@ struct Members {
     id_map: HashMap<u64, String>,
 }
 
+// shared by every handle of a session
+struct SessionState {
+    member_id: AtomicU64, // member serving the queries of the session
+    watermark: AtomicU64, // highest log id seen by the session
+}
+
 struct RaftClientInner {
     qry_meta: QryMeta,
     members: RwLock<Members>,
@@ -57,16 +275,79 @@ struct RaftClientInner {
     last_log_id: AtomicU64,
     last_log_term: AtomicU64,
     service_id: u64,
+    config: RaftClientConfig,
+    commit_watchers: RwLock<Vec<CommitWatcher>>,
+    conn_stats: RwLock<HashMap<u64, (u64, i64)>>, // member id -> (requests sent, last used)
+    discovery_gate: Mutex<()>,
+    leader_epoch: AtomicU64, // bumped by every successful update_info
+    leader_term: AtomicU64, // highest term announced by a leader elected event
+    pinned_leader: AtomicU64, // every command goes to this member when not 0
+    health: RwLock<HashMap<u64, (u32, Option<Instant>)>>, // member id -> (failures, blacklisted at)
+    read_consistency: RwLock<ReadConsistency>, // used by queries that do not ask for one
+    command_log: Mutex<VecDeque<CommandRecord>>,
+    query_cache: Mutex<(HashMap<CacheKey, Vec<u8>>, VecDeque<CacheKey>)>, // results, oldest first
+    in_flight: Mutex<HashMap<QueryKey, Vec<oneshot::Sender<Result<Attempted, ExecError>>>>>,
+    clock_skew: RwLock<HashMap<u64, i64>>, // member id -> member clock minus local clock, in ms
+    sessions: RwLock<HashMap<u64, Arc<SessionState>>>, // session key hash -> state
+    op_overrides: RwLock<HashMap<(u64, u64), OpType>>, // (sm id, fn id) -> op type sent
+    last_cluster_info: RwLock<Option<ClientClusterInfo>>, // from the last successful discovery
+    discovery_paused: AtomicBool,
+    slots: Mutex<(usize, VecDeque<oneshot::Sender<Slot>>)>, // calls in flight, calls waiting
+}
+
+// Removes a coalesced query from the in flight map however it ends, even when dropped half way.
+// Waiters get its result, or are let go to send their own query if there is none.
+struct CoalescedLeader {
+    client: Arc<RaftClientInner>,
+    key: QueryKey,
+    res: Option<Result<Attempted, ExecError>>,
+}
+
+impl Drop for CoalescedLeader {
+    fn drop(&mut self) {
+        let waiters = self.client.in_flight.lock().remove(&self.key).unwrap_or_default();
+        if let Some(ref res) = self.res {
+            for waiter in waiters {
+                let _ = waiter.send(res.clone());
+            }
+        }
+    }
+}
+
+// An in flight slot, freed when dropped
+struct Slot {
+    client: Arc<RaftClientInner>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        RaftClientInner::free_slot(&self.client);
+    }
 }
 
 pub struct RaftClient {
     inner: Arc<RaftClientInner>,
 }
 
+/// Calls tagged with a session key, see `RaftClient::session`
+#[derive(Clone)]
+pub struct ClientSession {
+    inner: Arc<RaftClientInner>,
+    state: Arc<SessionState>,
+}
+
 impl RaftClient {
     pub fn new(servers: &Vec<String>, service_id: u64) -> Result<Arc<RaftClient>, ClientError> {
+        Self::with_config(servers, service_id, RaftClientConfig::default())
+    }
+
+    pub fn with_config(
+        servers: &Vec<String>,
+        service_id: u64,
+        config: RaftClientConfig,
+    ) -> Result<Arc<RaftClient>, ClientError> {
         Ok(Arc::new(RaftClient {
-            inner: RaftClientInner::new(servers, service_id)?,
+            inner: RaftClientInner::new(servers, service_id, config)?,
         }))
     }
 
@@ -82,6 +363,126 @@ impl RaftClient {
         RaftClientInner::execute(self.inner.clone(), sm_id, msg)
     }
 
+    /// Like `execute`, queries are served with the given consistency instead of the default
+    pub fn execute_with_consistency<R, M>(
+        &self,
+        sm_id: u64,
+        msg: M,
+        consistency: ReadConsistency,
+    ) -> Box<Future<Item = R, Error = ExecError>>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        RaftClientInner::execute_with_consistency(self.inner.clone(), sm_id, msg, consistency)
+    }
+
+    /// Run a command only if the leader is still in `expected_term`.
+    /// Fails with `ExecError::TermChanged` once leadership has moved on,
+    /// so a deposed leader cannot apply it.
+    pub fn command_fenced<R, M>(
+        &self,
+        sm_id: u64,
+        msg: M,
+        expected_term: u64,
+    ) -> Box<Future<Item = R, Error = ExecError>>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let inner = self.inner.clone();
+        RaftClientInner::guarded(self.inner.clone(), move || {
+            RaftClientInner::command_fenced(inner, sm_id, msg, expected_term)
+        })
+    }
+
+    /// The last commands sent through `execute`, oldest first.
+    /// Empty unless `RaftClientConfig::command_log_size` is set.
+    pub fn recent_commands(&self) -> Vec<CommandRecord> {
+        self.inner.command_log.lock().iter().cloned().collect()
+    }
+
+    /// Answer a query from the local cache right away and verify it against the cluster in the
+    /// background. `on_update` runs with the verified value when it differs from the cached one
+    /// or when nothing was cached, and with the error when the query could not be verified.
+    /// Only results verified this way are cached, those of a state machine are dropped when this
+    /// client commits a command to it. Anything but a query fails with `ExecError::NotQuery`.
+    pub fn query_optimistic<R, M, F>(
+        &self,
+        sm_id: u64,
+        msg: M,
+        on_update: F,
+    ) -> Result<Option<R>, ExecError>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+        F: Fn(Result<R, ExecError>) + Send + 'static,
+    {
+        RaftClientInner::query_optimistic(self.inner.clone(), sm_id, msg, on_update)
+    }
+
+    /// Consistency of queries sent by `execute`, `ReadYourWrites` unless changed
+    pub fn set_default_read_consistency(&self, consistency: ReadConsistency) {
+        *self.inner.read_consistency.write() = consistency;
+    }
+
+    /// Send calls to `fn_id` of state machine `sm_id` as `op` instead of the declared type.
+    /// Routing a command as a query sends it to any member and it is never written to the log,
+    /// so only override functions that do not change state. Function ids are hashes unique
+    /// within one state machine only, hence the state machine in the key: the same id
+    /// elsewhere can be an unrelated function.
+    pub fn override_op_type(&self, sm_id: u64, fn_id: u64, op: OpType) {
+        self.inner.op_overrides.write().insert((sm_id, fn_id), op);
+    }
+
+    /// Execute a function with payload bytes built by the caller, returns the raw result
+    pub fn execute_raw(
+        &self,
+        sm_id: u64,
+        fn_id: u64,
+        op: OpType,
+        data: Vec<u8>,
+    ) -> Box<Future<Item = Vec<u8>, Error = ExecError>> {
+        RaftClientInner::execute_raw(self.inner.clone(), sm_id, fn_id, op, data)
+    }
+
+    /// Read served by a follower, after it caught up to the leader.
+    /// The commit index of the leader is taken as the read index once the leader confirmed
+    /// it still takes itself for the leader, otherwise the call fails with `NotLeader`.
+    /// Best effort only: a leader deposed without knowing it yet hands out an older index.
+    pub fn query_read_index<R, M>(
+        &self,
+        sm_id: u64,
+        msg: M,
+    ) -> Box<Future<Item = R, Error = ExecError>>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let inner = self.inner.clone();
+        RaftClientInner::guarded(self.inner.clone(), move || {
+            RaftClientInner::query_read_index(inner, sm_id, msg)
+        })
+    }
+
+    /// Read from up to `k` followers and only accept a result that a majority of them agree on,
+    /// both payload and last log id. Guards against a single stale follower.
+    pub fn query_quorum_confirmed<R, M>(
+        &self,
+        sm_id: u64,
+        msg: M,
+        k: usize,
+    ) -> Box<Future<Item = R, Error = ExecError>>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let inner = self.inner.clone();
+        RaftClientInner::guarded(self.inner.clone(), move || {
+            RaftClientInner::query_quorum_confirmed(inner, sm_id, msg, k)
+        })
+    }
+
     pub fn can_callback() -> bool {
         RaftClientInner::can_callback()
     }
@@ -111,6 +512,116 @@ impl RaftClient {
         self.inner.leader_id.load(ORDERING)
     }
 
+    /// Send every command to `server_id` only, without leader discovery or switching.
+    /// Failures, including `NotLeader`, are returned to the caller as is until `unpin_leader`.
+    pub fn pin_leader(&self, server_id: u64) {
+        self.inner.pinned_leader.store(server_id, ORDERING);
+    }
+
+    pub fn unpin_leader(&self) {
+        self.inner.pinned_leader.store(0, ORDERING);
+    }
+
+    /// A query any member answers however far behind it is, like with `ReadConsistency::Stale`,
+    /// along with how far behind this client that member was
+    pub fn query_detailed<R, M>(
+        &self,
+        sm_id: u64,
+        msg: M,
+    ) -> Box<Future<Item = (R, Staleness), Error = ExecError>>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let inner = self.inner.clone();
+        RaftClientInner::guarded(self.inner.clone(), move || {
+            RaftClientInner::query_detailed(inner, sm_id, msg)
+        })
+    }
+
+    /// Block until a member serving queries reports a last log id of at least `up_to_log_id`,
+    /// or fail with `ExecError::Timeout`. The log id is then taken as seen by this client,
+    /// so later `ReadYourWrites` queries are only answered by members that have it.
+    pub fn wait_committed(&self, up_to_log_id: u64, timeout: Duration) -> Result<(), ExecError> {
+        self.inner.wait_committed(up_to_log_id, timeout)
+    }
+
+    /// Stop looking for members and the leader, for maintenance where membership churns.
+    /// Calls keep going to the last known members and leader, a command that would need
+    /// another leader fails with `ExecError::DiscoveryPaused` instead.
+    pub fn pause_discovery(&self) {
+        self.inner.discovery_paused.store(true, ORDERING);
+    }
+
+    pub fn resume_discovery(&self) {
+        self.inner.discovery_paused.store(false, ORDERING);
+    }
+
+    /// Number of calls holding a slot, calls waiting for one are not counted
+    pub fn in_flight_count(&self) -> usize {
+        self.inner.slots.lock().0
+    }
+
+    /// Cluster info as answered to the last successful discovery, for debugging
+    pub fn last_cluster_info(&self) -> Option<ClientClusterInfo> {
+        self.inner.last_cluster_info.read().clone()
+    }
+
+    /// Number of members in the cluster as of the last discovery. All members vote,
+    /// the cluster has no non-voting members to leave out.
+    pub fn replication_factor(&self) -> usize {
+        self.inner.members.read().id_map.len()
+    }
+
+    /// Clock skew of the members seen during discovery, in ms relative to the local clock.
+    /// Positive when the member is ahead. Members are measured when they answer a cluster info
+    /// request, large skews tend to come before unstable elections.
+    pub fn member_clock_skew(&self) -> Vec<(u64, i64)> {
+        let mut skews: Vec<(u64, i64)> = self
+            .inner
+            .clock_skew
+            .read()
+            .iter()
+            .map(|(id, skew)| (*id, *skew))
+            .collect();
+        skews.sort();
+        skews
+    }
+
+    /// Handle for calls that have to read their own writes, from any thread.
+    /// Every handle for `key` sends its queries to the same member and shares one watermark,
+    /// so a query in the session never sees an older state than an earlier call did.
+    /// Sessions are kept for the lifetime of the client.
+    pub fn session(&self, key: &str) -> ClientSession {
+        ClientSession {
+            inner: self.inner.clone(),
+            state: self.inner.session_state(key),
+        }
+    }
+
+    /// Term of the last leader elected event seen, 0 if leader tracking is off
+    pub fn leader_term(&self) -> u64 {
+        self.inner.leader_term.load(ORDERING)
+    }
+
+    /// Subscribe to leader elections so the leader id is updated as soon as a new leader takes
+    /// over, instead of after a command bounces off the old one.
+    /// Needs `prepare_subscription` like any other subscription.
+    pub fn enable_leader_tracking(
+        &self,
+    ) -> Box<Future<Item = Result<SubscriptionReceipt, SubscriptionError>, Error = ExecError>> {
+        let inner = Arc::downgrade(&self.inner);
+        self.subscribe(
+            CONFIG_SM_ID,
+            on_leader_elected::new(),
+            move |res: Result<(u64, u64), ()>| {
+                if let (Some(inner), Ok((leader_id, term))) = (inner.upgrade(), res) {
+                    inner.leader_elected(leader_id, term);
+                }
+            },
+        )
+    }
+
     pub fn leader_client(&self) -> Option<(u64, Client)> {
         self.inner.leader_client()
     }
@@ -118,12 +629,66 @@ impl RaftClient {
     pub fn current_leader_rpc_client(&self) -> impl Future<Item = Arc<rpc::RPCClient>, Error = ()> {
         RaftClientInner::current_leader_rpc_client(self.inner.clone())
     }
+
+    /// The exact entry this client would send for the call, for replay and inspection tools
+    pub fn build_log_entry(&self, sm_id: u64, fn_id: u64, data: &[u8]) -> LogEntry {
+        self.inner.gen_log_entry(sm_id, fn_id, data)
+    }
+
+    /// Called with the new log id every time the last log id seen by this client advances
+    pub fn watch_commit_progress<F>(&self, f: F)
+    where
+        F: Fn(u64) + Send + Sync + 'static,
+    {
+        self.inner.commit_watchers.write().push(Box::new(f));
+    }
+
+    /// One entry for every member this client holds a connection to
+    pub fn connection_stats(&self) -> Vec<ConnectionStat> {
+        self.inner.connection_stats()
+    }
+
+    /// Members that failed too many requests in a row, with the time they were blacklisted.
+    /// A member leaves the list after a successful request or background probe.
+    pub fn blacklisted_members(&self) -> Vec<(u64, Instant)> {
+        self.inner
+            .health
+            .read()
+            .iter()
+            .filter_map(|(id, &(_, since))| since.map(|since| (*id, since)))
+            .collect()
+    }
+}
+
+impl ClientSession {
+    /// Commands go to the leader as usual. Queries go to the member of the session and
+    /// fall back to the leader when that member has not caught up with the session.
+    pub fn execute<R, M>(&self, sm_id: u64, msg: M) -> Box<Future<Item = R, Error = ExecError>>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let (inner, state) = (self.inner.clone(), self.state.clone());
+        RaftClientInner::guarded(self.inner.clone(), move || {
+            RaftClientInner::session_execute(inner, state, sm_id, msg)
+        })
+    }
+
+    pub fn member_id(&self) -> u64 {
+        self.state.member_id.load(ORDERING)
+    }
+
+    /// Highest log id seen by any call in the session
+    pub fn watermark(&self) -> u64 {
+        self.state.watermark.load(ORDERING)
+    }
 }
 
 impl RaftClientInner {
     pub fn new(
         servers: &Vec<String>,
         service_id: u64,
+        config: RaftClientConfig,
     ) -> Result<Arc<RaftClientInner>, ClientError> {
         let client = Arc::new(RaftClientInner {
             qry_meta: QryMeta {
@@ -137,7 +702,34 @@ impl RaftClientInner {
             last_log_id: AtomicU64::new(0),
             last_log_term: AtomicU64::new(0),
             service_id,
+            config,
+            commit_watchers: RwLock::new(Vec::new()),
+            conn_stats: RwLock::new(HashMap::new()),
+            discovery_gate: Mutex::new(()),
+            leader_epoch: AtomicU64::new(0),
+            leader_term: AtomicU64::new(0),
+            pinned_leader: AtomicU64::new(0),
+            health: RwLock::new(HashMap::new()),
+            read_consistency: RwLock::new(ReadConsistency::ReadYourWrites),
+            command_log: Mutex::new(VecDeque::new()),
+            query_cache: Mutex::new((HashMap::new(), VecDeque::new())),
+            in_flight: Mutex::new(HashMap::new()),
+            clock_skew: RwLock::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
+            op_overrides: RwLock::new(HashMap::new()),
+            last_cluster_info: RwLock::new(None),
+            discovery_paused: AtomicBool::new(false),
+            slots: Mutex::new((0, VecDeque::new())),
         });
+        if client.config.blacklist_after > 0 {
+            Self::start_probe(Arc::downgrade(&client));
+        }
+        if client.config.keep_all_connected {
+            Self::start_keeper(Arc::downgrade(&client));
+        }
+        if let Some(interval) = client.config.leader_probe_interval {
+            Self::start_leader_watch(Arc::downgrade(&client), interval);
+        }
         Self::update_info(client.clone(), HashSet::from_iter(servers.iter().cloned()))
             .wait()
             .map(move |_| client)
@@ -162,7 +754,7 @@ impl RaftClientInner {
         for server_addr in servers {
             let id = hash_str(&server_addr);
             if !members.clients.contains_key(&id) {
-                match rpc::DEFAULT_CLIENT_POOL.get(&server_addr) {
+                match this.connect(&server_addr) {
                     Ok(client) => {
                         let members = members.mutate();
                         members
@@ -174,8 +766,14 @@ impl RaftClientInner {
                     }
                 }
             }
-            if let Ok(Ok(info)) = await!(members.clients.get(&id).unwrap().c_server_cluster_info())
-            {
+            let client = members.clients.get(&id).unwrap().clone();
+            let timeout = this.config.rpc_timeout;
+            let sent = get_time();
+            let res = await!(with_timeout(move || client.c_server_cluster_info(), timeout));
+            if let Ok(Ok(info)) = res {
+                // assume the member read its clock half way through the round trip
+                let local = sent + (get_time() - sent) / 2;
+                this.clock_skew.write().insert(id, info.timestamp - local);
                 if info.leader_id != 0 {
                     return Ok((Some(info), members));
                 }
@@ -186,9 +784,13 @@ impl RaftClientInner {
 
     #[async(boxed)]
     fn update_info(this: Arc<Self>, servers: HashSet<String>) -> Result<(), ClientError> {
+        if this.discovery_paused.load(ORDERING) {
+            return Err(ClientError::DiscoveryPaused);
+        }
         let (cluster_info, members) = await!(Self::cluster_info(this.clone(), servers)).unwrap();
         match cluster_info {
             Some(info) => {
+                *this.last_cluster_info.write() = Some(info.clone());
                 let remote_members = info.members;
                 let mut remote_ids = HashSet::with_capacity(remote_members.len());
                 let mut members = members.mutate();
@@ -203,61 +805,553 @@ impl RaftClientInner {
                 }
                 let ids_to_remove = connected_ids.difference(&remote_ids);
                 for id in ids_to_remove {
+                    // the member left the cluster, close the connection instead of pooling it
+                    debug!("CLIENT: member {} removed, dropping connection", id);
                     members.clients.remove(id);
+                    rpc::DEFAULT_CLIENT_POOL.remove(*id);
+                    this.health.write().remove(id);
+                    this.clock_skew.write().remove(id);
                 }
                 for id in remote_ids.difference(&connected_ids) {
                     let addr = members.id_map.get(id).unwrap().clone();
                     if !members.clients.contains_key(id) {
-                        if let Ok(client) = rpc::DEFAULT_CLIENT_POOL.get(&addr) {
+                        if let Ok(client) = this.connect(&addr) {
                             members
                                 .clients
                                 .insert(*id, AsyncServiceClient::new(this.service_id, &client));
                         }
                     }
                 }
-                this.leader_id.store(info.leader_id, ORDERING);
-                Ok(())
+                this.leader_id.store(info.leader_id, ORDERING);
+                this.leader_epoch.fetch_add(1, ORDERING);
+                Ok(())
+            }
+            None => Err(ClientError::ServerUnreachable),
+        }
+    }
+
+    #[async(boxed)]
+    pub fn execute<R, M>(this: Arc<Self>, sm_id: u64, msg: M) -> Result<R, ExecError>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let codec = this.config.codec.clone();
+        let (fn_id, op, req_data) = this.encode_msg(msg)?;
+        let data = await!(Self::execute_raw(this, sm_id, fn_id, op, req_data))?;
+        M::decode_return(&*codec, &data)
+    }
+
+    #[async(boxed)]
+    pub fn execute_with_consistency<R, M>(
+        this: Arc<Self>,
+        sm_id: u64,
+        msg: M,
+        consistency: ReadConsistency,
+    ) -> Result<R, ExecError>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let codec = this.config.codec.clone();
+        let (fn_id, op, req_data) = this.encode_msg(msg)?;
+        let data = await!(Self::execute_raw_with(
+            this,
+            sm_id,
+            fn_id,
+            op,
+            req_data,
+            consistency
+        ))?;
+        M::decode_return(&*codec, &data)
+    }
+
+    #[async(boxed)]
+    pub fn command_fenced<R, M>(
+        this: Arc<Self>,
+        sm_id: u64,
+        msg: M,
+        expected_term: u64,
+    ) -> Result<R, ExecError>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let codec = this.config.codec.clone();
+        let (fn_id, _, data) = this.encode_msg(msg)?;
+        let (res, _) = await!(Self::command(
+            this.clone(),
+            sm_id,
+            fn_id,
+            data,
+            None,
+            Some(expected_term),
+            rand::random(),
+            Vec::new()
+        ))?;
+        res.and_then(|data| M::decode_return(&*codec, &data))
+    }
+
+    pub fn execute_raw(
+        this: Arc<Self>,
+        sm_id: u64,
+        fn_id: u64,
+        op: OpType,
+        data: Vec<u8>,
+    ) -> Box<Future<Item = Vec<u8>, Error = ExecError>> {
+        let consistency = *this.read_consistency.read();
+        Self::execute_raw_with(this, sm_id, fn_id, op, data, consistency)
+    }
+
+    #[async(boxed)]
+    fn execute_raw_with(
+        this: Arc<Self>,
+        sm_id: u64,
+        fn_id: u64,
+        op: OpType,
+        req_data: Vec<u8>,
+        consistency: ReadConsistency,
+    ) -> Result<Vec<u8>, ExecError> {
+        let op = this.op_type(sm_id, fn_id, op);
+        let tracer = this.config.tracer.clone();
+        let token = rand::random();
+        // raw payloads do not go through encode_msg
+        this.check_payload_size(&req_data)?;
+        let trace = tracer.start_span(sm_id, fn_id, op);
+        let start = Instant::now();
+        let client = this.clone();
+        let call = move || match op {
+            OpType::QUERY if client.config.coalesce_queries => {
+                Self::coalesced_query(client, sm_id, fn_id, req_data, trace, consistency)
+            }
+            OpType::QUERY => Self::query(
+                client,
+                sm_id,
+                fn_id,
+                req_data,
+                trace,
+                consistency,
+                Vec::new(),
+            ),
+            OpType::COMMAND | OpType::SUBSCRIBE => match client.config.hedge_after {
+                Some(delay) => {
+                    Self::hedged_command(client, sm_id, fn_id, req_data, trace, token, delay)
+                }
+                None => Self::command(
+                    client,
+                    sm_id,
+                    fn_id,
+                    req_data,
+                    trace,
+                    None,
+                    token,
+                    Vec::new(),
+                ),
+            },
+        };
+        let response = await!(Self::guarded(this.clone(), call));
+        let retries = match response {
+            Ok((_, retries)) => retries,
+            Err(ExecError::ExhaustedRetries { ref attempts }) => attempts.len(),
+            Err(_) => 0,
+        };
+        let succeeded = match response {
+            Ok((Ok(_), _)) => true,
+            _ => false,
+        };
+        let outcome = match (succeeded, retries) {
+            (false, _) => CallOutcome::Failed,
+            (true, 0) => CallOutcome::Success,
+            (true, _) => CallOutcome::Retried,
+        };
+        this.config.metrics.record_latency(op, outcome, start.elapsed());
+        if op != OpType::QUERY {
+            this.log_command(CommandRecord {
+                sm_id,
+                fn_id,
+                token,
+                outcome,
+                timestamp: get_time(),
+            });
+        }
+        tracer.end_span(CallSpan {
+            sm_id,
+            fn_id,
+            op,
+            leader_id: this.leader_id.load(ORDERING),
+            retries,
+            succeeded,
+        });
+        match response {
+            Ok((data, _)) => data,
+            Err(e) => Err(e),
+        }
+    }
+
+    // The first of a set of identical queries is sent, the rest wait for its result
+    #[async(boxed)]
+    fn coalesced_query(
+        this: Arc<Self>,
+        sm_id: u64,
+        fn_id: u64,
+        data: Vec<u8>,
+        trace: Option<Vec<u8>>,
+        consistency: ReadConsistency,
+    ) -> Result<Attempted, ExecError> {
+        let key = (sm_id, fn_id, hash_bytes(&data), consistency as u8);
+        let waiting = {
+            let mut in_flight = this.in_flight.lock();
+            if in_flight.contains_key(&key) {
+                let (tx, rx) = oneshot::channel();
+                in_flight.get_mut(&key).unwrap().push(tx);
+                Some(rx)
+            } else {
+                in_flight.insert(key, Vec::new());
+                None
+            }
+        };
+        if let Some(rx) = waiting {
+            return match await!(rx) {
+                Ok(res) => res,
+                // the query waited on was abandoned before it finished, send our own
+                Err(_) => await!(Self::query(
+                    this,
+                    sm_id,
+                    fn_id,
+                    data,
+                    trace,
+                    consistency,
+                    Vec::new()
+                )),
+            };
+        }
+        let mut leader = CoalescedLeader {
+            client: this.clone(),
+            key,
+            res: None,
+        };
+        let res = await!(Self::query(
+            this.clone(),
+            sm_id,
+            fn_id,
+            data,
+            trace,
+            consistency,
+            Vec::new()
+        ));
+        leader.res = Some(res.clone());
+        res
+    }
+
+    #[async(boxed)]
+    fn session_execute<R, M>(
+        this: Arc<Self>,
+        session: Arc<SessionState>,
+        sm_id: u64,
+        msg: M,
+    ) -> Result<R, ExecError>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let codec = this.config.codec.clone();
+        let (fn_id, op, data) = this.encode_msg(msg)?;
+        let op = this.op_type(sm_id, fn_id, op);
+        let res = match op {
+            OpType::QUERY => await!(Self::session_query(
+                this.clone(),
+                session,
+                sm_id,
+                fn_id,
+                data
+            ))?,
+            OpType::COMMAND | OpType::SUBSCRIBE => {
+                let (res, _) = await!(Self::command(
+                    this.clone(),
+                    sm_id,
+                    fn_id,
+                    data,
+                    None,
+                    None,
+                    rand::random(),
+                    Vec::new()
+                ))?;
+                // the log id of the command itself is not returned,
+                // but the last log id seen by the client is at least as high
+                swap_when_greater(&session.watermark, this.last_log_id.load(ORDERING));
+                res
+            }
+        };
+        res.and_then(|data| M::decode_return(&*codec, &data))
+    }
+
+    // Ask the session member first, it refuses with LeftBehind when it is behind the watermark
+    #[async(boxed)]
+    fn session_query(
+        this: Arc<Self>,
+        session: Arc<SessionState>,
+        sm_id: u64,
+        fn_id: u64,
+        data: Vec<u8>,
+    ) -> Result<ExecResult, ExecError> {
+        let watermark = session.watermark.load(ORDERING);
+        let mut targets = vec![session.member_id.load(ORDERING)];
+        let leader_id = this.leader_id.load(ORDERING);
+        if targets[0] != leader_id {
+            if this.is_blacklisted(targets[0]) {
+                targets.clear();
+            }
+            targets.push(leader_id);
+        }
+        let mut attempts = Vec::new();
+        for member_id in targets {
+            let client = match this.members.read().clients.get(&member_id) {
+                Some(client) => client.clone(),
+                None => continue,
+            };
+            this.record_request(member_id);
+            let mut entry = this.gen_log_entry(sm_id, fn_id, &data);
+            entry.id = watermark;
+            let timeout = this.config.rpc_timeout;
+            let res = await!(with_timeout(move || client.c_query(entry), timeout));
+            this.record_health(member_id, reachable(&res));
+            match res {
+                Ok(Ok(ClientQryResponse::Success {
+                    data,
+                    last_log_term,
+                    last_log_id,
+                })) => {
+                    this.observe_log(last_log_id, last_log_term);
+                    swap_when_greater(&session.watermark, last_log_id);
+                    return Ok(data);
+                }
+                Ok(Ok(ClientQryResponse::LeftBehind)) => attempts.push(RetryReason::LeftBehind),
+                Ok(Err(())) => attempts.push(RetryReason::ServerError),
+                Err(e) => attempts.push(rpc_retry_reason(&e)),
+            }
+        }
+        if attempts.is_empty() {
+            Err(ExecError::ServersUnreachable)
+        } else {
+            Err(ExecError::ExhaustedRetries { attempts })
+        }
+    }
+
+    #[async(boxed)]
+    fn query_detailed<R, M>(
+        this: Arc<Self>,
+        sm_id: u64,
+        msg: M,
+    ) -> Result<(R, Staleness), ExecError>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let codec = this.config.codec.clone();
+        let (fn_id, _, data) = this.encode_msg(msg)?;
+        let pos = this.qry_meta.pos.fetch_add(1, ORDERING);
+        let (member_id, client) = {
+            let members = this.members.read();
+            let ids = this.routable_ids(&members);
+            match ids
+                .get(pos as usize % max(ids.len(), 1))
+                .and_then(|id| members.clients.get(id).map(|client| (*id, client.clone())))
+            {
+                Some(target) => target,
+                None => return Err(ExecError::ServersUnreachable),
+            }
+        };
+        let watermark = this.last_log_id.load(ORDERING);
+        let mut entry = this.gen_log_entry(sm_id, fn_id, &data);
+        // accept whatever the member has, the caller learns how old it is
+        entry.id = 0;
+        entry.term = 0;
+        this.record_request(member_id);
+        let timeout = this.config.rpc_timeout;
+        let res = await!(with_timeout(move || client.c_query(entry), timeout));
+        this.record_health(member_id, reachable(&res));
+        match res {
+            Ok(Ok(ClientQryResponse::Success {
+                data,
+                last_log_term,
+                last_log_id,
+            })) => {
+                this.observe_log(last_log_id, last_log_term);
+                let staleness = Staleness {
+                    member_id,
+                    member_log_id: last_log_id,
+                    watermark,
+                };
+                data.and_then(|data| M::decode_return(&*codec, &data)).map(|res| (res, staleness))
+            }
+            Ok(Ok(ClientQryResponse::LeftBehind)) => Err(ExecError::ExhaustedRetries {
+                attempts: vec![RetryReason::LeftBehind],
+            }),
+            Ok(Err(())) => Err(ExecError::ServerError),
+            Err(e) => Err(rpc_exec_error(&e)),
+        }
+    }
+
+    #[async(boxed)]
+    pub fn query_read_index<R, M>(this: Arc<Self>, sm_id: u64, msg: M) -> Result<R, ExecError>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+    {
+        let (fn_id, _, data) = this.encode_msg(msg)?;
+        let timeout = this.config.rpc_timeout;
+        let read_index = match await!(Self::current_leader_client(this.clone())) {
+            Ok((leader_id, leader)) => {
+                match await!(with_timeout(move || leader.c_server_cluster_info(), timeout)) {
+                    Ok(Ok(ref info)) if info.leader_id != leader_id => {
+                        this.leader_id.store(info.leader_id, ORDERING);
+                        return Err(ExecError::NotLeader(info.leader_id));
+                    }
+                    Ok(Ok(info)) => info.commit_index,
+                    _ => return Err(ExecError::ServersUnreachable),
+                }
+            }
+            Err(()) => return Err(ExecError::ServersUnreachable),
+        };
+        let client = {
+            let members = this.members.read();
+            let leader_id = this.leader_id.load(ORDERING);
+            let followers: Vec<_> = members
+                .clients
+                .iter()
+                .filter(|&(id, _)| *id != leader_id && !this.is_blacklisted(*id))
+                .collect();
+            let pos = this.qry_meta.pos.fetch_add(1, ORDERING) as usize;
+            match followers.get(pos % max(followers.len(), 1)) {
+                Some(&(id, client)) => {
+                    this.record_request(*id);
+                    client.clone()
+                }
+                None => match members.clients.get(&leader_id) {
+                    Some(client) => client.clone(),
+                    None => return Err(ExecError::ServersUnreachable),
+                },
+            }
+        };
+        let mut entry = this.gen_log_entry(sm_id, fn_id, &data);
+        entry.id = max(entry.id, read_index);
+        for _ in 0..READ_INDEX_RETRY {
+            let (client, entry) = (client.clone(), entry.clone());
+            match await!(with_timeout(move || client.c_query(entry), timeout)) {
+                Ok(Ok(ClientQryResponse::Success {
+                    data,
+                    last_log_term,
+                    last_log_id,
+                })) => {
+                    this.observe_log(last_log_id, last_log_term);
+                    return data.and_then(|data| M::decode_return(&*this.config.codec, &data));
+                }
+                Ok(Ok(ClientQryResponse::LeftBehind)) => {
+                    // follower has not reached the read index yet
+                    let _ = await!(TIMER.sleep(Duration::from_millis(READ_INDEX_WAIT_MS)));
+                }
+                Ok(Err(())) => return Err(ExecError::ServerError),
+                Err(e) => return Err(rpc_exec_error(&e)),
             }
-            None => Err(ClientError::ServerUnreachable),
         }
+        Err(ExecError::TooManyRetry)
     }
 
     #[async(boxed)]
-    pub fn execute<R, M>(this: Arc<Self>, sm_id: u64, msg: M) -> Result<R, ExecError>
+    pub fn query_quorum_confirmed<R, M>(
+        this: Arc<Self>,
+        sm_id: u64,
+        msg: M,
+        k: usize,
+    ) -> Result<R, ExecError>
     where
         R: 'static,
         M: RaftMsg<R> + 'static,
     {
-        let (fn_id, op, req_data) = msg.encode();
-        let response = match op {
-            OpType::QUERY => await!(Self::query(this, sm_id, fn_id, req_data, 0)),
-            OpType::COMMAND | OpType::SUBSCRIBE => {
-                await!(Self::command(this, sm_id, fn_id, req_data, 0))
+        let (fn_id, _, data) = this.encode_msg(msg)?;
+        for _ in 0..QUORUM_READ_RETRY {
+            let clients = this.quorum_read_clients(k);
+            let asked = clients.len();
+            if asked == 0 {
+                return Err(ExecError::ServersUnreachable);
             }
-        };
-        match response {
-            Ok(data) => match data {
-                Ok(data) => Ok(M::decode_return(&data)),
-                Err(e) => Err(e),
-            },
-            Err(e) => Err(e),
+            let entry = this.gen_log_entry(sm_id, fn_id, &data);
+            let timeout = this.config.rpc_timeout;
+            let mut votes: Vec<((u64, Vec<u8>), usize)> = Vec::new();
+            for client in clients {
+                let entry = entry.clone();
+                if let Ok(Ok(ClientQryResponse::Success {
+                    data: Ok(data),
+                    last_log_term,
+                    last_log_id,
+                })) = await!(with_timeout(move || client.c_query(entry), timeout))
+                {
+                    this.observe_log(last_log_id, last_log_term);
+                    let answer = (last_log_id, data);
+                    match votes.iter().position(|&(ref voted, _)| *voted == answer) {
+                        Some(i) => votes[i].1 += 1,
+                        None => votes.push((answer, 1)),
+                    }
+                }
+            }
+            let agreed = votes
+                .into_iter()
+                .find(|&(_, count)| count * 2 > asked)
+                .map(|((_, data), _)| data);
+            if let Some(data) = agreed {
+                return M::decode_return(&*this.config.codec, &data);
+            }
+            // followers disagree, possibly still catching up
+            let _ = await!(TIMER.sleep(Duration::from_millis(READ_INDEX_WAIT_MS)));
         }
+        Err(ExecError::TooManyRetry)
+    }
+
+    // up to k followers starting from the query round robin position, or the leader alone
+    fn quorum_read_clients(&self, k: usize) -> Vec<Client> {
+        let members = self.members.read();
+        let leader_id = self.leader_id.load(ORDERING);
+        let followers: Vec<_> = members
+            .clients
+            .iter()
+            .filter(|&(id, _)| *id != leader_id && !self.is_blacklisted(*id))
+            .collect();
+        let pos = self.qry_meta.pos.fetch_add(1, ORDERING) as usize;
+        let chosen: Vec<(u64, Client)> = if followers.is_empty() {
+            members
+                .clients
+                .get(&leader_id)
+                .map(|client| (leader_id, client.clone()))
+                .into_iter()
+                .collect()
+        } else {
+            (0..min(k, followers.len()))
+                .map(|i| followers[(pos + i) % followers.len()])
+                .map(|(id, client)| (*id, client.clone()))
+                .collect()
+        };
+        chosen
+            .into_iter()
+            .map(|(id, client)| {
+                self.record_request(id);
+                client
+            })
+            .collect()
     }
 
     pub fn can_callback() -> bool {
         CALLBACK.read().is_some()
     }
-    fn get_sub_key<M, R>(&self, sm_id: u64, msg: M) -> SubKey
+    // the key and the encoded pattern it was hashed from
+    fn get_sub_key<M, R>(&self, sm_id: u64, msg: M) -> (SubKey, Vec<u8>)
     where
         M: RaftMsg<R> + 'static,
         R: 'static,
     {
         let raft_sid = self.service_id;
-        let (fn_id, pattern_id) = {
-            let (fn_id, _, pattern_data) = msg.encode();
-            (fn_id, hash_bytes(pattern_data.as_slice()))
-        };
-        return (raft_sid, sm_id, fn_id, pattern_id);
+        let (fn_id, _, pattern_data) = msg.encode(&*self.config.codec);
+        let pattern_id = hash_bytes(pattern_data.as_slice());
+        return ((raft_sid, sm_id, fn_id, pattern_id), pattern_data);
     }
     #[async(boxed)]
     pub fn get_callback(this: Arc<Self>) -> Result<Arc<SubscriptionService>, SubscriptionError> {
@@ -285,8 +1379,12 @@ impl RaftClientInner {
             Ok(c) => c,
             Err(e) => return Ok(Err(e)),
         };
-        let key = this.get_sub_key(sm_id, msg);
-        let wrapper_fn = move |data: Vec<u8>| f(M::decode_return(&data));
+        let (key, pattern) = this.get_sub_key(sm_id, msg);
+        let codec = this.config.codec.clone();
+        let wrapper_fn = move |data: Vec<u8>| match M::decode_return(&*codec, &data) {
+            Ok(res) => f(res),
+            Err(e) => warn!("Cannot decode subscription message: {:?}", e),
+        };
         let cluster_subs = await!(Self::execute(
             this.clone(),
             CONFIG_SM_ID,
@@ -296,7 +1394,7 @@ impl RaftClientInner {
             Ok(Ok(sub_id)) => {
                 let mut subs_map = callback.subs.write();
                 let mut subs_lst = subs_map.entry(key).or_insert_with(|| Vec::new());
-                subs_lst.push((Box::new(wrapper_fn), sub_id));
+                subs_lst.push((Box::new(wrapper_fn), sub_id, pattern));
                 Ok(Ok((key, sub_id)))
             }
             Ok(Err(_)) => Ok(Err(SubscriptionError::RemoteError)),
@@ -352,27 +1450,84 @@ impl RaftClientInner {
         sm_id: u64,
         fn_id: u64,
         data: Vec<u8>,
-        depth: usize,
-    ) -> Result<ExecResult, ExecError> {
+        trace: Option<Vec<u8>>,
+        consistency: ReadConsistency,
+        mut attempts: Vec<RetryReason>,
+    ) -> Result<Attempted, ExecError> {
         let pos = this.qry_meta.pos.fetch_add(1, ORDERING);
         let members = await!(this.members.read_async()).unwrap();
         let num_members = members.clients.len();
         if num_members >= 1 {
             let res = {
-                await!(members
-                    .clients
-                    .values()
-                    .nth(pos as usize % num_members)
-                    .unwrap()
-                    .c_query(this.gen_log_entry(sm_id, fn_id, &data)))
+                let target = match consistency {
+                    ReadConsistency::Linearizable => {
+                        let leader_id = this.leader_id.load(ORDERING);
+                        members
+                            .clients
+                            .get(&leader_id)
+                            .map(|client| (leader_id, client.clone()))
+                    }
+                    ReadConsistency::Stale | ReadConsistency::ReadYourWrites => {
+                        let ids = this.routable_ids(&members);
+                        let id = ids[pos as usize % ids.len()];
+                        members.clients.get(&id).map(|client| (id, client.clone()))
+                    }
+                };
+                let (member_id, client) = match target {
+                    Some(target) => target,
+                    None => return Err(ExecError::ServersUnreachable),
+                };
+                this.record_request(member_id);
+                let mut entry = this.gen_log_entry(sm_id, fn_id, &data);
+                entry.trace_context = trace.clone();
+                if consistency == ReadConsistency::Stale {
+                    // accept whatever the member has
+                    entry.id = 0;
+                    entry.term = 0;
+                }
+                let timeout = this.config.rpc_timeout;
+                let res = await!(with_timeout(move || client.c_query(entry), timeout));
+                this.record_health(member_id, reachable(&res));
+                res
             };
             match res {
                 Ok(Ok(res)) => match res {
                     ClientQryResponse::LeftBehind => {
-                        if depth >= num_members {
-                            Err(ExecError::TooManyRetry)
+                        let depth = attempts.len();
+                        attempts.push(RetryReason::LeftBehind);
+                        let behind = attempts
+                            .iter()
+                            .rev()
+                            .take_while(|reason| **reason == RetryReason::LeftBehind)
+                            .count();
+                        // the leader gets one attempt of its own, however many were used
+                        let escalate = consistency != ReadConsistency::Linearizable
+                            && this
+                                .config
+                                .left_behind_escalation
+                                .map_or(false, |after| behind >= after);
+                        let consistency = if escalate {
+                            ReadConsistency::Linearizable
                         } else {
-                            await!(Self::query(this.clone(), sm_id, fn_id, data, depth + 1))
+                            consistency
+                        };
+                        if depth >= num_members && !escalate {
+                            Err(ExecError::ExhaustedRetries { attempts })
+                        } else {
+                            // no need to keep other requests from changing members while waiting
+                            drop(members);
+                            if let Some(backoff) = this.retry_backoff(attempts.len()) {
+                                let _ = await!(TIMER.sleep(backoff));
+                            }
+                            await!(Self::query(
+                                this.clone(),
+                                sm_id,
+                                fn_id,
+                                data,
+                                trace,
+                                consistency,
+                                attempts
+                            ))
                         }
                     }
                     ClientQryResponse::Success {
@@ -380,12 +1535,36 @@ impl RaftClientInner {
                         last_log_term,
                         last_log_id,
                     } => {
-                        swap_when_greater(&this.last_log_id, last_log_id);
-                        swap_when_greater(&this.last_log_term, last_log_term);
-                        Ok(data)
+                        this.observe_log(last_log_id, last_log_term);
+                        Ok((data, attempts.len()))
                     }
                 },
-                _ => Err(ExecError::Unknown),
+                Ok(Err(())) => Err(ExecError::ServerError),
+                Err(e) => {
+                    let reason = rpc_retry_reason(&e);
+                    // a member not answering in time is passed over like one left behind,
+                    // once per member. Only the leader answers linearizable queries
+                    if reason != RetryReason::Timeout
+                        || consistency == ReadConsistency::Linearizable
+                        || attempts.len() + 1 >= num_members
+                    {
+                        return Err(rpc_exec_error(&e));
+                    }
+                    attempts.push(reason);
+                    drop(members);
+                    if let Some(backoff) = this.retry_backoff(attempts.len()) {
+                        let _ = await!(TIMER.sleep(backoff));
+                    }
+                    await!(Self::query(
+                        this.clone(),
+                        sm_id,
+                        fn_id,
+                        data,
+                        trace,
+                        consistency,
+                        attempts
+                    ))
+                }
             }
         } else {
             Err(ExecError::ServersUnreachable)
@@ -398,84 +1577,785 @@ impl RaftClientInner {
         sm_id: u64,
         fn_id: u64,
         data: Vec<u8>,
-        depth: usize,
-    ) -> Result<ExecResult, ExecError> {
+        trace: Option<Vec<u8>>,
+        fence_term: Option<u64>,
+        token: u64, // shared by every copy of the command, it is applied once
+        mut attempts: Vec<RetryReason>,
+    ) -> Result<Attempted, ExecError> {
         enum FailureAction {
             SwitchLeader,
             NotCommitted,
             UpdateInfo,
-            NotLeader,
+            NotLeader(u64), // leader epoch when the command was sent
+            Throttled(u64),
             Retry,
         }
-        let failure = {
-            if depth > 0 {
-                let members = this.members.read();
-                let num_members = members.clients.len();
-                if depth >= max(num_members, 5) {
-                    return Err(ExecError::TooManyRetry);
+        let depth = attempts.len();
+        let (failure, reason) = {
+            if depth > 0 && depth >= this.command_retry_budget() {
+                return Err(ExecError::ExhaustedRetries { attempts });
+            }
+            let pinned = this.pinned_leader.load(ORDERING);
+            if pinned != 0 {
+                let entry = {
+                    let mut entry = this.gen_log_entry(sm_id, fn_id, &data);
+                    entry.trace_context = trace;
+                    entry.fence_term = fence_term;
+                    entry.idempotency_token = Some(token);
+                    entry
                 };
+                let data = await!(Self::pinned_command(this.clone(), pinned, entry))?;
+                this.forget_queries(sm_id);
+                return Ok((data, depth));
             }
+            let epoch = this.leader_epoch.load(ORDERING);
             match await!(Self::current_leader_client(this.clone())) {
                 Ok((leader_id, client)) => {
-                    match await!(client.c_command(this.gen_log_entry(sm_id, fn_id, &data))) {
+                    this.record_request(leader_id);
+                    let mut entry = this.gen_log_entry(sm_id, fn_id, &data);
+                    entry.trace_context = trace.clone();
+                    entry.fence_term = fence_term;
+                    entry.idempotency_token = Some(token);
+                    let timeout = this.config.rpc_timeout;
+                    let res = await!(with_timeout(move || client.c_command(entry), timeout));
+                    this.record_health(leader_id, reachable(&res));
+                    match res {
                         Ok(Ok(ClientCmdResponse::Success {
                             data,
                             last_log_term,
                             last_log_id,
                         })) => {
-                            swap_when_greater(&this.last_log_id, last_log_id);
-                            swap_when_greater(&this.last_log_term, last_log_term);
-                            return Ok(data);
+                            this.observe_log(last_log_id, last_log_term);
+                            this.forget_queries(sm_id);
+                            return Ok((data, depth));
                         }
                         Ok(Ok(ClientCmdResponse::NotLeader(leader_id))) => {
                             this.leader_id.store(leader_id, ORDERING);
-                            FailureAction::NotLeader
+                            (FailureAction::NotLeader(epoch), RetryReason::NotLeader)
+                        }
+                        Ok(Ok(ClientCmdResponse::NotCommitted)) => {
+                            (FailureAction::NotCommitted, RetryReason::NotCommitted)
                         }
-                        Ok(Ok(ClientCmdResponse::NotCommitted)) => FailureAction::NotCommitted,
+                        Ok(Ok(ClientCmdResponse::TermChanged(term))) => {
+                            debug!("CLIENT: fenced command rejected in term {}", term);
+                            return Err(ExecError::TermChanged);
+                        }
+                        Ok(Ok(ClientCmdResponse::Throttled { retry_after_ms })) => (
+                            FailureAction::Throttled(retry_after_ms),
+                            RetryReason::Throttled,
+                        ),
                         Err(e) => {
-                            debug!("CLIENT: E1 - {} - {:?}", leader_id, e);
-                            FailureAction::SwitchLeader // need switch server for leader
+                            warn!("CLIENT: E1 - {} - {:?}", leader_id, e);
+                            // need switch server for leader
+                            (FailureAction::SwitchLeader, rpc_retry_reason(&e))
                         }
                         Ok(Err(e)) => {
-                            debug!("CLIENT: E2 - {} - {:?}", leader_id, e);
-                            FailureAction::SwitchLeader // need switch server for leader
+                            warn!("CLIENT: E2 - {} - {:?}", leader_id, e);
+                            // need switch server for leader
+                            (FailureAction::SwitchLeader, RetryReason::ServerError)
                         }
                     }
                 }
-                Err(()) => FailureAction::UpdateInfo, // need update members
+                // need update members
+                Err(()) => (FailureAction::UpdateInfo, RetryReason::NoLeader),
             }
         }; //
+        if this.discovery_paused.load(ORDERING) {
+            match failure {
+                FailureAction::SwitchLeader
+                | FailureAction::NotLeader(_)
+                | FailureAction::UpdateInfo => return Err(ExecError::DiscoveryPaused),
+                _ => {}
+            }
+        }
+        attempts.push(reason);
+        let backoff = match failure {
+            // the server told us how long to wait
+            FailureAction::Throttled(_) => None,
+            _ if attempts.len() >= this.command_retry_budget() => None,
+            _ => this.retry_backoff(attempts.len()),
+        };
         match failure {
             FailureAction::SwitchLeader => {
                 let members = this.members.read();
-                let num_members = members.clients.len();
                 let pos = this.qry_meta.pos.load(ORDERING);
                 let leader_id = this.leader_id.load(ORDERING);
-                let index = members
-                    .clients
-                    .keys()
-                    .nth(pos as usize % num_members)
-                    .unwrap();
-                this.leader_id.compare_and_swap(leader_id, *index, ORDERING);
-                debug!("CLIENT: Switch leader");
+                let index = match this.leader_candidate(&members, leader_id, depth) {
+                    Some(id) => id,
+                    None => {
+                        let ids = this.routable_ids(&members);
+                        ids[pos as usize % ids.len()]
+                    }
+                };
+                // a single attempt, another command may already have switched the leader
+                let _ = this.leader_id.compare_exchange(
+                    leader_id,
+                    index,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                warn!("CLIENT: Switch leader from {} to {}", leader_id, index);
+            }
+            FailureAction::NotLeader(epoch) => {
+                await!(Self::confirm_leader(this.clone(), epoch));
+            }
+            FailureAction::Throttled(retry_after_ms) => {
+                if depth + 1 >= this.command_retry_budget() {
+                    return Err(ExecError::Throttled);
+                }
+                let _ = await!(TIMER.sleep(Duration::from_millis(retry_after_ms)));
             }
             _ => {}
         }
-        await!(Self::command(this, sm_id, fn_id, data, depth + 1))
+        if let Some(backoff) = backoff {
+            let _ = await!(TIMER.sleep(backoff));
+        }
+        await!(Self::command(
+            this,
+            sm_id,
+            fn_id,
+            data,
+            trace,
+            fence_term,
+            token,
+            attempts
+        ))
+    }
+
+    // No retries and no switching, the caller asked for this member
+    #[async(boxed)]
+    fn pinned_command(
+        this: Arc<Self>,
+        server_id: u64,
+        entry: LogEntry,
+    ) -> Result<ExecResult, ExecError> {
+        let client = match this.members.read().clients.get(&server_id) {
+            Some(client) => client.clone(),
+            None => return Err(ExecError::ServersUnreachable),
+        };
+        this.record_request(server_id);
+        let timeout = this.config.rpc_timeout;
+        let res = await!(with_timeout(move || client.c_command(entry), timeout));
+        this.record_health(server_id, reachable(&res));
+        match res {
+            Ok(Ok(ClientCmdResponse::Success {
+                data,
+                last_log_term,
+                last_log_id,
+            })) => {
+                this.observe_log(last_log_id, last_log_term);
+                Ok(data)
+            }
+            Ok(Ok(ClientCmdResponse::NotLeader(leader_id))) => Err(ExecError::NotLeader(leader_id)),
+            Ok(Ok(ClientCmdResponse::NotCommitted)) => Err(ExecError::NotCommitted),
+            Ok(Ok(ClientCmdResponse::Throttled { .. })) => Err(ExecError::Throttled),
+            Ok(Ok(ClientCmdResponse::TermChanged(_))) => Err(ExecError::TermChanged),
+            Ok(Err(())) => Err(ExecError::ServerError),
+            Err(e) => Err(rpc_exec_error(&e)),
+        }
+    }
+
+    // Let only one of the commands that saw the leader change refresh cluster info,
+    // the others wait for it and retry against the confirmed leader
+    #[async(boxed)]
+    fn confirm_leader(this: Arc<Self>, seen_epoch: u64) -> Result<(), ()> {
+        let _gate = await!(this.discovery_gate.lock_async())?;
+        if this.leader_epoch.load(ORDERING) == seen_epoch {
+            let servers = this.known_servers();
+            await!(Self::update_info(this.clone(), servers));
+        }
+        Ok(())
+    }
+
+    fn hedged_command(
+        this: Arc<Self>,
+        sm_id: u64,
+        fn_id: u64,
+        data: Vec<u8>,
+        trace: Option<Vec<u8>>,
+        token: u64,
+        delay: Duration,
+    ) -> Box<Future<Item = Attempted, Error = ExecError>> {
+        let primary = exec(Self::command(
+            this.clone(),
+            sm_id,
+            fn_id,
+            data.clone(),
+            trace.clone(),
+            None,
+            token,
+            Vec::new(),
+        ));
+        let hedge = exec(TIMER.sleep(delay).then(move |_| {
+            // leader is too slow, find out who the leader is now and send again
+            let servers = this.known_servers();
+            Self::update_info(this.clone(), servers)
+                .then(move |_| {
+                    Self::command(this, sm_id, fn_id, data, trace, None, token, Vec::new())
+                })
+        }));
+        box primary.select(hedge).then(
+            |res| -> Box<Future<Item = Attempted, Error = ExecError>> {
+                match res {
+                    Ok((res, _)) => box future::ok(res),
+                    Err((_, other)) => box other,
+                }
+            },
+        )
+    }
+
+    fn observe_log(&self, last_log_id: u64, last_log_term: u64) {
+        swap_when_greater(&self.last_log_term, last_log_term);
+        if swap_when_greater(&self.last_log_id, last_log_id) {
+            for watcher in self.commit_watchers.read().iter() {
+                watcher(last_log_id);
+            }
+        }
+    }
+
+    fn wait_committed(&self, up_to_log_id: u64, timeout: Duration) -> Result<(), ExecError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let member = {
+                let members = self.members.read();
+                let ids = self.routable_ids(&members);
+                if ids.is_empty() {
+                    return Err(ExecError::ServersUnreachable);
+                }
+                // same rotation as queries
+                let pos = self.qry_meta.pos.fetch_add(1, ORDERING);
+                members.clients.get(&ids[pos as usize % ids.len()]).cloned()
+            };
+            if let Some(member) = member {
+                // never wait past the deadline for an answer
+                let now = Instant::now();
+                let remaining = if now < deadline {
+                    deadline - now
+                } else {
+                    Duration::from_millis(0)
+                };
+                let timeout = self.config.rpc_timeout.map_or(remaining, |t| min(t, remaining));
+                let probe = with_timeout(move || member.c_server_cluster_info(), Some(timeout));
+                if let Ok(Ok(info)) = probe.wait() {
+                    if info.last_log_id >= up_to_log_id {
+                        self.observe_log(info.last_log_id, info.last_log_term);
+                        return Ok(());
+                    }
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ExecError::Timeout);
+            }
+            thread::sleep(min(deadline - now, Duration::from_millis(COMMIT_POLL_MS)));
+        }
+    }
+
+    // next member to try as leader by configured priority, none for round-robin
+    fn leader_candidate(&self, members: &Members, failed_id: u64, depth: usize) -> Option<u64> {
+        let mut candidates: Vec<u64> = self
+            .config
+            .leader_priority
+            .iter()
+            .map(|addr| hash_str(addr))
+            .filter(|id| members.clients.contains_key(id))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let others: Vec<u64> = members
+            .clients
+            .keys()
+            .filter(|id| !candidates.contains(id))
+            .cloned()
+            .collect();
+        candidates.extend(others);
+        candidates.retain(|id| *id != failed_id && !self.is_blacklisted(*id));
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(candidates[depth % candidates.len()])
+        }
+    }
+
+    fn log_command(&self, record: CommandRecord) {
+        let capacity = self.config.command_log_size;
+        if capacity == 0 {
+            return;
+        }
+        let mut log = self.command_log.lock();
+        if log.len() >= capacity {
+            log.pop_front();
+        }
+        log.push_back(record);
+    }
+
+    fn record_request(&self, member_id: u64) {
+        let mut stats = self.conn_stats.write();
+        let stat = stats.entry(member_id).or_insert((0, 0));
+        stat.0 += 1;
+        stat.1 = get_time();
+    }
+
+    fn connect(&self, addr: &String) -> io::Result<Arc<rpc::RPCClient>> {
+        match self.config.connect_timeout {
+            Some(timeout) => rpc::DEFAULT_CLIENT_POOL.get_with_connect_timeout(addr, timeout),
+            None => rpc::DEFAULT_CLIENT_POOL.get(addr),
+        }
+    }
+
+    fn is_blacklisted(&self, member_id: u64) -> bool {
+        self.health
+            .read()
+            .get(&member_id)
+            .map_or(false, |&(_, since)| since.is_some())
+    }
+
+    // Members to route to in id order, all of them when every member is blacklisted
+    fn routable_ids(&self, members: &Members) -> Vec<u64> {
+        let ids: Vec<u64> = members
+            .clients
+            .keys()
+            .filter(|id| !self.is_blacklisted(**id))
+            .cloned()
+            .collect();
+        if ids.is_empty() {
+            members.clients.keys().cloned().collect()
+        } else {
+            ids
+        }
+    }
+
+    fn record_health(&self, member_id: u64, reachable: bool) {
+        let threshold = self.config.blacklist_after;
+        if threshold == 0 {
+            return;
+        }
+        let mut health = self.health.write();
+        if reachable {
+            health.remove(&member_id);
+            return;
+        }
+        let entry = health.entry(member_id).or_insert((0, None));
+        entry.0 += 1;
+        if entry.0 >= threshold && entry.1.is_none() {
+            debug!("CLIENT: blacklisted member {}", member_id);
+            entry.1 = Some(Instant::now());
+        }
+    }
+
+    // Rehabilitate blacklisted members once they answer again, stops with the client
+    fn start_probe(this: Weak<Self>) {
+        let interval = match this.upgrade() {
+            Some(client) => client.config.probe_interval,
+            None => return,
+        };
+        thread::Builder::new()
+            .name("Raft client member probe".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let client = match this.upgrade() {
+                    Some(client) => client,
+                    None => return,
+                };
+                let blacklisted: Vec<u64> = client
+                    .health
+                    .read()
+                    .iter()
+                    .filter(|&(_, &(_, since))| since.is_some())
+                    .map(|(id, _)| *id)
+                    .collect();
+                let blacklisted: Vec<(u64, Client)> = {
+                    let members = client.members.read();
+                    blacklisted
+                        .into_iter()
+                        .filter_map(|id| members.clients.get(&id).map(|c| (id, c.clone())))
+                        .collect()
+                };
+                let timeout = client.config.rpc_timeout;
+                for (id, member) in blacklisted {
+                    let probe = with_timeout(move || member.c_server_cluster_info(), timeout);
+                    if let Ok(Ok(_)) = probe.wait() {
+                        debug!("CLIENT: member {} recovered", id);
+                        client.health.write().remove(&id);
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    fn query_optimistic<R, M, F>(
+        this: Arc<Self>,
+        sm_id: u64,
+        msg: M,
+        on_update: F,
+    ) -> Result<Option<R>, ExecError>
+    where
+        R: 'static,
+        M: RaftMsg<R> + 'static,
+        F: Fn(Result<R, ExecError>) + Send + 'static,
+    {
+        let codec = this.config.codec.clone();
+        let (fn_id, op, data) = this.encode_msg(msg)?;
+        if this.op_type(sm_id, fn_id, op) != OpType::QUERY {
+            return Err(ExecError::NotQuery);
+        }
+        let key = (sm_id, fn_id, hash_bytes(data.as_slice()));
+        let cached = this.query_cache.lock().0.get(&key).cloned();
+        let local = match cached {
+            Some(ref cached) => Some(M::decode_return(&*codec, cached)?),
+            None => None,
+        };
+        let client = this.clone();
+        spawn(
+            Self::execute_raw(this, sm_id, fn_id, op, data).then(move |res| {
+                match res {
+                    Ok(ref fresh) if cached.as_ref() == Some(fresh) => {}
+                    Ok(fresh) => {
+                        client.cache_query(key, fresh.clone());
+                        on_update(M::decode_return(&*codec, &fresh));
+                    }
+                    Err(e) => on_update(Err(e)),
+                }
+                Ok::<(), ()>(())
+            }),
+        );
+        Ok(local)
+    }
+
+    // keep a verified result of an optimistic query, evicting the oldest over the limit
+    fn cache_query(&self, key: CacheKey, data: Vec<u8>) {
+        let capacity = self.config.query_cache_size;
+        if capacity == 0 {
+            return;
+        }
+        let mut cache = self.query_cache.lock();
+        let (ref mut results, ref mut order) = *cache;
+        if results.insert(key, data).is_none() {
+            order.push_back(key);
+            if order.len() > capacity {
+                let oldest = order.pop_front().unwrap();
+                results.remove(&oldest);
+            }
+        }
+    }
+
+    // cached results of a state machine are stale once a command changed it
+    fn forget_queries(&self, sm_id: u64) {
+        let mut cache = self.query_cache.lock();
+        let (ref mut results, ref mut order) = *cache;
+        results.retain(|&(id, _, _), _| id != sm_id);
+        order.retain(|&(id, _, _)| id != sm_id);
+    }
+
+    // Re-discover the leader once it missed enough probes in a row, stops with the client
+    fn start_leader_watch(this: Weak<Self>, interval: Duration) {
+        let mut failures = 0;
+        thread::Builder::new()
+            .name("Raft client leader watch".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let client = match this.upgrade() {
+                    Some(client) => client,
+                    None => return,
+                };
+                let timeout = client.config.rpc_timeout;
+                let responded = match client.leader_client() {
+                    Some((_, leader)) => {
+                        let probe = with_timeout(move || leader.c_server_cluster_info(), timeout);
+                        match probe.wait() {
+                            Ok(Ok(_)) => true,
+                            _ => false,
+                        }
+                    }
+                    None => false,
+                };
+                if responded {
+                    failures = 0;
+                    continue;
+                }
+                failures += 1;
+                if failures >= client.config.leader_probe_failures {
+                    debug!("CLIENT: leader missed {} probes, re-discovering", failures);
+                    failures = 0;
+                    let servers = client.known_servers();
+                    let _ = Self::update_info(client.clone(), servers).wait();
+                }
+            })
+            .unwrap();
+    }
+
+    // Reconnect to members whose connection dropped and connect to every known member the
+    // client has no connection to, stops with the client
+    fn start_keeper(this: Weak<Self>) {
+        let interval = match this.upgrade() {
+            Some(client) => client.config.probe_interval,
+            None => return,
+        };
+        thread::Builder::new()
+            .name("Raft client connection keeper".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match this.upgrade() {
+                    Some(client) => {
+                        client.evict_dead();
+                        client.connect_missing();
+                    }
+                    None => return,
+                }
+            })
+            .unwrap();
+    }
+
+    // Drop the connections of members not answering a probe, from the pool too,
+    // so that connect_missing opens new ones
+    fn evict_dead(&self) {
+        let connected: Vec<(u64, Client)> = {
+            let members = self.members.read();
+            members
+                .clients
+                .iter()
+                .map(|(id, client)| (*id, client.clone()))
+                .collect()
+        };
+        // a probe hanging on a dead connection must not hold up the others
+        let timeout = Some(self.config.rpc_timeout.unwrap_or(self.config.probe_interval));
+        for (id, member) in connected {
+            let probed = member.clone();
+            let probe = with_timeout(move || probed.c_server_cluster_info(), timeout).wait();
+            if reachable(&probe) {
+                continue;
+            }
+            let mut members = self.members.write();
+            // another request may have replaced the connection meanwhile
+            if members.clients.get(&id).map_or(false, |c| Arc::ptr_eq(c, &member)) {
+                debug!("CLIENT: lost connection to member {}, reconnecting", id);
+                members.clients.remove(&id);
+                rpc::DEFAULT_CLIENT_POOL.remove(id);
+            }
+        }
+    }
+
+    fn connect_missing(&self) {
+        let missing: Vec<(u64, String)> = {
+            let members = self.members.read();
+            members
+                .id_map
+                .iter()
+                .filter(|&(id, _)| !members.clients.contains_key(id))
+                .map(|(id, addr)| (*id, addr.clone()))
+                .collect()
+        };
+        for (id, addr) in missing {
+            if let Ok(client) = self.connect(&addr) {
+                let mut members = self.members.write();
+                if members.id_map.contains_key(&id) {
+                    debug!("CLIENT: connected to member {}", id);
+                    let service_id = self.service_id;
+                    members
+                        .clients
+                        .entry(id)
+                        .or_insert_with(|| AsyncServiceClient::new(service_id, &client));
+                }
+            }
+        }
+    }
+
+    fn connection_stats(&self) -> Vec<ConnectionStat> {
+        let members = self.members.read();
+        let stats = self.conn_stats.read();
+        members
+            .clients
+            .keys()
+            .map(|id| {
+                let &(requests_sent, last_used) = stats.get(id).unwrap_or(&(0, 0));
+                ConnectionStat {
+                    member_id: *id,
+                    address: members.id_map.get(id).cloned().unwrap_or_default(),
+                    requests_sent,
+                    last_used,
+                }
+            })
+            .collect()
+    }
+
+    // every message is encoded here, so none is sent over the size limit
+    fn encode_msg<R, M>(&self, msg: M) -> Result<(u64, OpType, Vec<u8>), ExecError>
+    where
+        M: RaftMsg<R>,
+    {
+        let (fn_id, op, data) = msg.encode(&*self.config.codec);
+        self.check_payload_size(&data)?;
+        Ok((fn_id, op, data))
+    }
+
+    fn check_payload_size(&self, data: &[u8]) -> Result<(), ExecError> {
+        match self.config.max_payload_size {
+            Some(limit) if data.len() > limit => Err(ExecError::PayloadTooLarge {
+                size: data.len(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Every call made for the user goes through here. It waits for an in flight slot and holds
+    // it until the call is done, `operation_timeout` bounds the wait and the call together.
+    fn guarded<T, F>(this: Arc<Self>, call: F) -> Box<Future<Item = T, Error = ExecError>>
+    where
+        T: 'static,
+        F: FnOnce() -> Box<Future<Item = T, Error = ExecError>> + 'static,
+    {
+        let call = Self::acquire_slot(&this).and_then(move |slot| {
+            call().then(move |res| {
+                drop(slot);
+                res
+            })
+        });
+        this.within_operation_timeout(box call)
+    }
+
+    // Take an in flight slot right away, or queue to be handed one when a slot is freed
+    fn acquire_slot(this: &Arc<Self>) -> Box<Future<Item = Slot, Error = ExecError>> {
+        let mut slots = this.slots.lock();
+        let (ref mut in_flight, ref mut waiting) = *slots;
+        if this.config.max_in_flight.map_or(true, |limit| *in_flight < limit) {
+            *in_flight += 1;
+            box future::ok(Slot {
+                client: this.clone(),
+            })
+        } else if waiting.len() < this.config.max_queued {
+            let (tx, rx) = oneshot::channel();
+            waiting.push_back(tx);
+            // the sender is only dropped unsent with the client itself
+            box rx.map_err(|_| ExecError::Overloaded)
+        } else {
+            box future::err(ExecError::Overloaded)
+        }
+    }
+
+    // Hand the slot itself to the first waiting call, or give it back when none is waiting.
+    // A waiter that is gone, even after the handover, drops the slot and passes it on.
+    fn free_slot(this: &Arc<Self>) {
+        let waiter = {
+            let mut slots = this.slots.lock();
+            let next = slots.1.pop_front();
+            match next {
+                Some(waiter) => waiter,
+                None => {
+                    slots.0 -= 1;
+                    return;
+                }
+            }
+        };
+        let _ = waiter.send(Slot {
+            client: this.clone(),
+        });
+    }
+
+    // Stop waiting for a call that is still retrying once `operation_timeout` has passed
+    fn within_operation_timeout<T: 'static>(
+        &self,
+        call: Box<Future<Item = T, Error = ExecError>>,
+    ) -> Box<Future<Item = T, Error = ExecError>> {
+        match self.config.operation_timeout {
+            None => call,
+            Some(timeout) => box call.select2(TIMER.sleep(timeout)).then(|res| {
+                match res {
+                    Ok(future::Either::A((attempted, _))) => Ok(attempted),
+                    Err(future::Either::A((e, _))) => Err(e),
+                    _ => Err(ExecError::Timeout),
+                }
+            }),
+        }
+    }
+
+    fn command_retry_budget(&self) -> usize {
+        let members = self.members.read();
+        max(members.clients.len(), 5)
+    }
+
+    // Wait before the given retry, 1 being the first one. Drawn from the upper half of
+    // the exponential delay so clients failing together do not retry together.
+    fn retry_backoff(&self, retry: usize) -> Option<Duration> {
+        let base = duration_to_ms(self.config.base_backoff);
+        if retry == 0 || base == 0 {
+            return None;
+        }
+        let shift = min(retry - 1, 32) as u32;
+        let full = min(
+            base.saturating_mul(1 << shift),
+            duration_to_ms(self.config.max_backoff),
+        );
+        let half = full / 2;
+        Some(Duration::from_millis(half + rand::random::<u64>() % (full - half + 1)))
+    }
+
+    fn session_state(&self, key: &str) -> Arc<SessionState> {
+        let key_hash = hash_bytes(key.as_bytes());
+        if let Some(state) = self.sessions.read().get(&key_hash) {
+            return state.clone();
+        }
+        let member_id = {
+            let members = self.members.read();
+            let num_members = members.clients.len();
+            members
+                .clients
+                .keys()
+                .nth(key_hash as usize % max(num_members, 1))
+                .cloned()
+                .unwrap_or(0)
+        };
+        self.sessions
+            .write()
+            .entry(key_hash)
+            .or_insert_with(|| {
+                Arc::new(SessionState {
+                    member_id: AtomicU64::new(member_id),
+                    watermark: AtomicU64::new(0),
+                })
+            })
+            .clone()
+    }
+
+    fn op_type(&self, sm_id: u64, fn_id: u64, declared: OpType) -> OpType {
+        self.op_overrides
+            .read()
+            .get(&(sm_id, fn_id))
+            .cloned()
+            .unwrap_or(declared)
+    }
+
+    fn known_servers(&self) -> HashSet<String> {
+        let members = self.members.read();
+        HashSet::from_iter(members.id_map.values().cloned())
     }
 
-    fn gen_log_entry(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>) -> LogEntry {
+    fn gen_log_entry(&self, sm_id: u64, fn_id: u64, data: &[u8]) -> LogEntry {
         LogEntry {
             id: self.last_log_id.load(ORDERING),
             term: self.last_log_term.load(ORDERING),
             sm_id,
             fn_id,
-            data: data.clone(),
+            data: data.to_vec(),
+            trace_context: None,
+            fence_term: None,
+            idempotency_token: None,
         }
     }
     pub fn leader_id(&self) -> u64 {
         self.leader_id.load(ORDERING)
     }
+    fn leader_elected(&self, leader_id: u64, term: u64) {
+        // events can arrive out of order, a late one from an older term must not win
+        if term < self.leader_term.load(ORDERING) {
+            return;
+        }
+        debug!("CLIENT: leader {} elected for term {}", leader_id, term);
+        self.leader_term.store(term, ORDERING);
+        self.leader_id.store(leader_id, ORDERING);
+        self.leader_epoch.fetch_add(1, ORDERING);
+    }
     pub fn leader_client(&self) -> Option<(u64, Client)> {
         let members = self.members.read();
         let leader_id = self.leader_id();
@@ -494,10 +2374,7 @@ impl RaftClientInner {
             }
         }
         {
-            let servers = {
-                let members = this.members.read();
-                HashSet::from_iter(members.id_map.values().cloned())
-            };
+            let servers = this.known_servers();
             await!(Self::update_info(this.clone(), servers));
             let leader_id = this.leader_id.load(ORDERING);
             let members = this.members.read();
@@ -515,17 +2392,104 @@ impl RaftClientInner {
     }
 }
 
-fn swap_when_greater(atomic: &AtomicU64, value: u64) {
-    let mut orig_num = atomic.load(ORDERING);
+// returns true when the value was stored. Log ids and terms do not guard any other data,
+// so the loop needs no ordering beyond the atomic itself
+fn swap_when_greater(atomic: &AtomicU64, value: u64) -> bool {
+    let mut orig_num = atomic.load(Ordering::Relaxed);
     loop {
         if orig_num >= value {
-            return;
+            return false;
         }
-        let actual = atomic.compare_and_swap(orig_num, value, ORDERING);
-        if actual == orig_num {
-            return;
-        } else {
-            orig_num = actual;
+        match atomic.compare_exchange_weak(orig_num, value, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return true,
+            // lost to another writer or failed spuriously, compare against what is there now
+            Err(actual) => orig_num = actual,
+        }
+    }
+}
+
+fn reachable<T, E>(res: &Result<Result<T, E>, rpc::RPCError>) -> bool {
+    match *res {
+        Ok(Ok(_)) => true,
+        _ => false,
+    }
+}
+
+// Fail an RPC that is not answered in time the same way as one timing out on the connection.
+// With a timeout the call is made on the exec pool, a member in this process is called
+// through the shortcut and answers before the call returns.
+fn with_timeout<T, F>(
+    call: F,
+    timeout: Option<Duration>,
+) -> Box<Future<Item = T, Error = rpc::RPCError>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Box<Future<Item = T, Error = rpc::RPCError>> + 'static,
+{
+    match timeout {
+        None => call(),
+        Some(timeout) => box exec(future::lazy(call))
+            .select2(TIMER.sleep(timeout))
+            .then(|res| match res {
+                Ok(future::Either::A((item, _))) => Ok(item),
+                Err(future::Either::A((e, _))) => Err(e),
+                _ => Err(rpc::RPCError::IOError(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no response in time",
+                ))),
+            }),
+    }
+}
+
+// Keep what went wrong on the wire for the caller, a timeout reads the same as the operation timing out
+fn rpc_exec_error(e: &rpc::RPCError) -> ExecError {
+    match *e {
+        rpc::RPCError::IOError(ref e) if e.kind() == io::ErrorKind::TimedOut => ExecError::Timeout,
+        ref e => ExecError::Rpc(format!("{:?}", e)),
+    }
+}
+
+fn rpc_retry_reason(e: &rpc::RPCError) -> RetryReason {
+    match *e {
+        rpc::RPCError::IOError(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+            RetryReason::Timeout
         }
+        rpc::RPCError::IOError(_) => RetryReason::NetworkError,
+        rpc::RPCError::RequestError(_) => RetryReason::ServerError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swap_when_greater_concurrently() {
+        let atomic = Arc::new(AtomicU64::new(0));
+        let threads: Vec<_> = (0..8u64)
+            .map(|t| {
+                let atomic = atomic.clone();
+                thread::spawn(move || {
+                    let mut max_stored = 0;
+                    for i in 0..10000u64 {
+                        let value = i * 8 + t;
+                        if swap_when_greater(&atomic, value) {
+                            max_stored = max(max_stored, value);
+                        }
+                        // never goes back below what this thread has seen stored
+                        assert!(atomic.load(Ordering::Relaxed) >= max_stored);
+                    }
+                    max_stored
+                })
+            })
+            .collect();
+        let max_observed = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .max()
+            .unwrap();
+        assert_eq!(max_observed, 9999 * 8 + 7);
+        assert_eq!(atomic.load(Ordering::Relaxed), max_observed);
+        assert!(!swap_when_greater(&atomic, max_observed));
     }
 }
@@ -0,0 +1,60 @@
+use bincode;
+use bincode::Options;
+use erased_serde;
+use raft::state_machine::master::ExecError;
+use serde;
+
+pub type Decoding<'a> =
+    &'a mut for<'de> FnMut(&mut erased_serde::Deserializer<'de>) -> Result<(), erased_serde::Error>;
+
+/// Encodes state machine arguments and results, and the messages sent to subscribers.
+/// Servers and clients of a cluster have to be set up with the same codec.
+pub trait Codec: Send + Sync {
+    /// Panics if the value cannot be encoded, as `utils::bincode::serialize` does.
+    fn encode(&self, value: &erased_serde::Serialize) -> Vec<u8>;
+    /// Hands a deserializer over `data` to `decoding`, which reads the value out of it.
+    fn decode(&self, data: &[u8], decoding: Decoding) -> Result<(), String>;
+}
+
+pub struct BincodeCodec;
+
+// the same format as utils::bincode, so the default codec stays compatible with it
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+impl Codec for BincodeCodec {
+    fn encode(&self, value: &erased_serde::Serialize) -> Vec<u8> {
+        match bincode_options().serialize(value) {
+            Ok(data) => data,
+            Err(e) => panic!("Cannot serialize: {:?}", e),
+        }
+    }
+    fn decode(&self, data: &[u8], decoding: Decoding) -> Result<(), String> {
+        let mut de = bincode::Deserializer::from_slice(data, bincode_options());
+        decoding(&mut erased_serde::Deserializer::erase(&mut de)).map_err(|e| format!("{}", e))
+    }
+}
+
+pub fn encode<T>(codec: &Codec, value: &T) -> Vec<u8>
+where
+    T: serde::Serialize,
+{
+    codec.encode(value)
+}
+
+pub fn decode<T>(codec: &Codec, data: &[u8]) -> Result<T, ExecError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut value = None;
+    codec
+        .decode(data, &mut |de| {
+            value = Some(erased_serde::deserialize(de)?);
+            Ok(())
+        })
+        .map_err(ExecError::Decode)?;
+    value.ok_or_else(|| ExecError::Decode(format!("nothing decoded from {} bytes", data.len())))
+}
@@ -1,9 +1,13 @@
-use self::client::RaftClient;
-use self::state_machine::configs::commands::{del_member_, member_address, new_member_};
+use self::client::{ClientError, RaftClient, RaftClientConfig};
+use self::codec::{BincodeCodec, Codec};
+use self::state_machine::configs::commands::{
+    del_member_, member_address, new_member_, on_leader_elected,
+};
+use self::state_machine::callback::{AsyncServiceClient as CallbackClient, SubKey};
 use self::state_machine::configs::{RaftMember, CONFIG_SM_ID};
 use self::state_machine::master::{ExecError, ExecResult, MasterStateMachine, SubStateMachine};
 use self::state_machine::OpType;
-use bifrost_hasher::hash_str;
+use bifrost_hasher::{hash_bytes, hash_str};
 use num_cpus;
 use rand;
 use rand::distributions::{IndependentSample, Range};
@@ -30,6 +34,7 @@ use std::path::Path;
 #[macro_use]
 pub mod state_machine;
 pub mod client;
+pub mod codec;
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_DEFAULT_SERVICE) as u64;
 const MAX_LOG_CAPACITY: usize = 10;
@@ -45,8 +50,8 @@ def_bindings! {
 }
 
 pub trait RaftMsg<R>: Send + Sync {
-    fn encode(self) -> (u64, OpType, Vec<u8>);
-    fn decode_return(data: &Vec<u8>) -> R;
+    fn encode(self, codec: &Codec) -> (u64, OpType, Vec<u8>);
+    fn decode_return(codec: &Codec, data: &Vec<u8>) -> Result<R, ExecError>;
 }
 
 const CHECKER_MS: i64 = 10;
@@ -58,6 +63,9 @@ pub struct LogEntry {
     pub sm_id: u64,
     pub fn_id: u64,
     pub data: Vec<u8>,
+    pub trace_context: Option<Vec<u8>>, // opaque tracing context from the client, if any
+    pub fence_term: Option<u64>, // only accept the command in this term
+    pub idempotency_token: Option<u64>, // a command with a token already applied is skipped
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -69,6 +77,8 @@ pub enum ClientCmdResponse {
     },
     NotLeader(u64),
     NotCommitted,
+    Throttled { retry_after_ms: u64 }, // leader is overloaded
+    TermChanged(u64), // fenced command rejected, carries the current term
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientQryResponse {
@@ -81,10 +91,12 @@ pub enum ClientQryResponse {
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientClusterInfo {
-    members: Vec<(u64, String)>,
-    last_log_id: u64,
-    last_log_term: u64,
-    leader_id: u64,
+    pub members: Vec<(u64, String)>,
+    pub last_log_id: u64,
+    pub last_log_term: u64,
+    pub leader_id: u64,
+    pub timestamp: i64, // wall clock of the answering member, ms since the epoch
+    pub commit_index: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -195,6 +207,7 @@ pub struct RaftService {
     meta: RwLock<RaftMeta>,
     pub id: u64,
     pub options: Options,
+    pub codec: Arc<Codec>,
 }
 dispatch_rpc_service_functions!(RaftService);
 
@@ -212,6 +225,32 @@ enum RequestVoteResponse {
     NotGranted,
 }
 
+// clients tracking leadership, collected under the meta lock and notified after releasing it
+struct LeaderElected {
+    clients: Vec<Arc<CallbackClient>>,
+    key: SubKey,
+    pattern_data: Vec<u8>,
+    data: Vec<u8>,
+    workers: Arc<Mutex<ThreadPool>>,
+}
+
+impl LeaderElected {
+    fn notify(self) {
+        let LeaderElected {
+            clients,
+            key,
+            pattern_data,
+            data,
+            workers,
+        } = self;
+        workers.lock().execute(move || {
+            for client in clients {
+                let _ = client.notify(key, pattern_data.clone(), data.clone()).wait();
+            }
+        });
+    }
+}
+
 macro_rules! get_last_log_info {
     ($s: expr, $logs: expr) => {{
         let last_log = $logs.iter().next_back();
@@ -261,6 +300,10 @@ fn alter_term(meta: &mut RwLockWriteGuard<RaftMeta>, term: u64) {
 
 impl RaftService {
     pub fn new(opts: Options) -> Arc<RaftService> {
+        Self::new_with_codec(opts, Arc::new(BincodeCodec))
+    }
+    /// Clients of the service have to be set up with the same codec.
+    pub fn new_with_codec(opts: Options, codec: Arc<Codec>) -> Arc<RaftService> {
         let server_address = opts.address.clone();
         let server_id = hash_str(&server_address);
         let mut storage_entity = StorageEntity::new_with_options(&opts).unwrap();
@@ -269,7 +312,7 @@ impl RaftService {
         let mut logs = BTreeMap::new();
         let mut commit_index = 0;
         let mut last_applied = 0;
-        let mut master_sm = MasterStateMachine::new(opts.service_id);
+        let mut master_sm = MasterStateMachine::new(opts.service_id, codec.clone());
 
         if let &mut Some(ref mut storage) = &mut storage_entity {
             let mut snapshot_data = vec![];
@@ -301,6 +344,7 @@ impl RaftService {
             }),
             id: server_id,
             options: opts,
+            codec,
         };
         Arc::new(server_obj)
     }
@@ -392,11 +436,15 @@ impl RaftService {
             let logs = meta.logs.read();
             get_last_log_info!(self, logs)
         };
-        self.become_leader(&mut meta, last_log_id);
+        let elected = self.become_leader(&mut meta, last_log_id);
+        drop(meta);
+        if let Some(elected) = elected {
+            elected.notify();
+        }
     }
     pub fn join(&self, servers: &Vec<String>) -> Result<Result<(), ()>, ExecError> {
         debug!("Trying to join cluster with id {}", self.id);
-        let client = RaftClient::new(servers, self.options.service_id);
+        let client = self.cluster_client(servers);
         if let Ok(client) = client {
             let result = client
                 .execute(CONFIG_SM_ID, new_member_::new(&self.options.address))
@@ -415,6 +463,13 @@ impl RaftService {
             Err(ExecError::CannotConstructClient)
         }
     }
+    fn cluster_client(&self, servers: &Vec<String>) -> Result<Arc<RaftClient>, ClientError> {
+        let config = RaftClientConfig {
+            codec: self.codec.clone(),
+            ..RaftClientConfig::default()
+        };
+        RaftClient::with_config(servers, self.options.service_id, config)
+    }
     pub fn leave(&self) -> bool {
         let servers = self
             .cluster_info()
@@ -422,7 +477,7 @@ impl RaftService {
             .iter()
             .map(|&(_, ref address)| address.clone())
             .collect();
-        if let Ok(client) = RaftClient::new(&servers, self.options.service_id) {
+        if let Ok(client) = self.cluster_client(&servers) {
             client
                 .execute(CONFIG_SM_ID, del_member_::new(&self.options.address))
                 .wait();
@@ -455,6 +510,8 @@ impl RaftService {
             last_log_id,
             last_log_term,
             leader_id: meta.leader_id,
+            timestamp: get_time(),
+            commit_index: meta.commit_index,
         }
     }
     pub fn num_members(&self) -> usize {
@@ -590,7 +647,11 @@ impl RaftService {
                         RequestVoteResponse::Granted => {
                             granted += 1;
                             if is_majority(members, granted) {
-                                server.become_leader(&mut meta, last_log_id);
+                                let elected = server.become_leader(&mut meta, last_log_id);
+                                drop(meta);
+                                if let Some(elected) = elected {
+                                    elected.notify();
+                                }
                                 break;
                             }
                         }
@@ -610,7 +671,12 @@ impl RaftService {
         self.switch_membership(meta, Membership::Follower);
     }
 
-    fn become_leader(&self, meta: &mut RwLockWriteGuard<RaftMeta>, last_log_id: u64) {
+    // the election is to be notified once `meta` is released
+    fn become_leader(
+        &self,
+        meta: &mut RwLockWriteGuard<RaftMeta>,
+        last_log_id: u64,
+    ) -> Option<LeaderElected> {
         let leader_meta = RwLock::new(LeaderMeta::new());
         {
             let mut guard = leader_meta.write();
@@ -619,6 +685,29 @@ impl RaftService {
         }
         meta.leader_id = self.id;
         self.switch_membership(meta, Membership::Leader(leader_meta));
+        self.leader_elected(meta)
+    }
+
+    // tell clients tracking leadership without waiting for the state machine to catch up
+    fn leader_elected(&self, meta: &RwLockWriteGuard<RaftMeta>) -> Option<LeaderElected> {
+        let (fn_id, _, pattern_data) = on_leader_elected::new().encode(&*self.codec);
+        let pattern_id = hash_bytes(pattern_data.as_slice());
+        let key = (self.options.service_id, CONFIG_SM_ID, fn_id, pattern_id);
+        let clients = {
+            let sm = meta.state_machine.read();
+            let subs = sm.configs.subscriptions.read();
+            subs.subscribers_of(&key)
+        };
+        if clients.is_empty() {
+            return None;
+        }
+        Some(LeaderElected {
+            clients,
+            key,
+            pattern_data,
+            data: codec::encode(&*self.codec, &Ok::<_, ()>((self.id, meta.term))),
+            workers: meta.workers.clone(),
+        })
     }
 
     fn send_followers_heartbeat(
@@ -1041,6 +1130,11 @@ impl Service for RaftService {
         if !is_leader(&meta) {
             return box future::finished(ClientCmdResponse::NotLeader(meta.leader_id));
         }
+        if let Some(fence_term) = entry.fence_term {
+            if fence_term != meta.term {
+                return box future::finished(ClientCmdResponse::TermChanged(meta.term));
+            }
+        }
         let (new_log_id, new_log_term) = self.leader_append_log(&meta, &mut entry);
         let mut data = match entry.sm_id {
             // special treats for membership changes
@@ -7,18 +7,29 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use utils::time::get_time;
 
+// callback, subscription id, encoded pattern subscribed to
+pub type Subscription = (Box<Fn(Vec<u8>) + Send + Sync>, u64, Vec<u8>);
+
 pub struct SubscriptionService {
-    pub subs: RwLock<HashMap<SubKey, Vec<(Box<Fn(Vec<u8>) + Send + Sync>, u64)>>>,
+    pub subs: RwLock<HashMap<SubKey, Vec<Subscription>>>,
     pub server_address: String,
     pub session_id: u64,
 }
 
 impl Service for SubscriptionService {
-    fn notify(&self, key: SubKey, data: Vec<u8>) -> Box<Future<Item = (), Error = ()>> {
+    fn notify(
+        &self,
+        key: SubKey,
+        pattern: Vec<u8>,
+        data: Vec<u8>,
+    ) -> Box<Future<Item = (), Error = ()>> {
         let subs = self.subs.read();
         if let Some(subs) = subs.get(&key) {
-            for &(ref fun, _) in subs {
-                fun(data.clone());
+            // patterns with colliding hashes share the key, only call the ones that match
+            for &(ref fun, _, ref sub_pattern) in subs {
+                if *sub_pattern == pattern {
+                    fun(data.clone());
+                }
             }
         }
         box future::finished(())
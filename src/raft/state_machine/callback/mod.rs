@@ -8,5 +8,6 @@ pub type SubKey = (u64, u64, u64, u64);
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_SM_CALLBACK_DEFAULT_SERVICE) as u64;
 
 service! {
-    rpc notify(key: SubKey, data: Vec<u8>);
+    // pattern is the encoded pattern of the event, keys only carry its hash
+    rpc notify(key: SubKey, pattern: Vec<u8>, data: Vec<u8>);
 }
@@ -3,13 +3,13 @@ use super::super::OpType;
 use super::*;
 use bifrost_hasher::{hash_bytes, hash_str};
 use parking_lot::RwLock;
+use raft::codec;
 use raft::{RaftService, IS_LEADER};
 use rpc;
 use serde;
 use std::any::Any;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use utils::bincode;
 
 use futures::Future;
 
@@ -86,6 +86,18 @@ impl Subscriptions {
         Ok(sub_id)
     }
 
+    pub fn subscribers_of(&self, key: &SubKey) -> Vec<Arc<AsyncServiceClient>> {
+        match self.subscriptions.get(key) {
+            Some(sub_ids) => sub_ids
+                .iter()
+                .filter_map(|sub_id| self.sub_suber.get(sub_id))
+                .filter_map(|suber_id| self.subscribers.get(suber_id))
+                .map(|subscriber| subscriber.client.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
     pub fn remove_subscriber(&mut self, suber_id: u64) {
         let suber_subs = if let Some(sub_ids) = self.suber_subs.get(&suber_id) {
             sub_ids.iter().cloned().collect()
@@ -164,7 +176,8 @@ impl SMCallback {
         if !IS_LEADER.get() {
             return Err(NotifyError::IsNotLeader);
         }
-        let (fn_id, op_type, pattern_data) = msg.encode();
+        let codec = &*self.raft_service.codec;
+        let (fn_id, op_type, pattern_data) = msg.encode(codec);
         match op_type {
             OpType::SUBSCRIBE => {
                 let pattern_id = hash_bytes(&pattern_data.as_slice());
@@ -186,9 +199,9 @@ impl SMCallback {
                         .map(|sub_id| {
                             if let Some(subscriber_id) = svr_subs.sub_suber.get(&sub_id) {
                                 if let Some(subscriber) = svr_subs.subscribers.get(&subscriber_id) {
-                                    let data = bincode::serialize(&message);
+                                    let data = codec::encode(codec, &message);
                                     let client = &subscriber.client;
-                                    Ok(client.notify(key, data))
+                                    Ok(client.notify(key, pattern_data.clone(), data))
                                 } else {
                                     Err(NotifyError::CannotFindSubscriber)
                                 }
@@ -223,7 +236,7 @@ impl SMCallback {
         F: Fn(&R) + Sync + Send + 'static,
         R: 'static,
     {
-        let (_, op_type, pattern_data) = msg.encode();
+        let (_, op_type, pattern_data) = msg.encode(&*self.raft_service.codec);
         match op_type {
             OpType::SUBSCRIBE => {
                 let pattern_id = hash_bytes(&pattern_data.as_slice());
@@ -41,6 +41,9 @@ raft_state_machine! {
 
     def cmd subscribe(key: SubKey, address: String, session_id: u64) -> u64;
     def cmd unsubscribe(sub_id: u64);
+
+    // (leader id, term), sent by a server when it becomes leader
+    def sub on_leader_elected() -> (u64, u64);
 }
 
 impl StateMachineCmds for Configures {
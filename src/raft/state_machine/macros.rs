@@ -57,35 +57,40 @@ macro_rules! raft_fn_op_type {
 
 #[macro_export]
 macro_rules! raft_dispatch_fn {
-    ($fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {{
-        let decoded: ($($in_,)*) = $crate::utils::bincode::deserialize($d);
+    ($fn_name:ident $s: ident $d: ident $c: ident ( $( $arg:ident : $in_:ty ),* )) => {{
+        let decoded: ($($in_,)*) = match $crate::raft::codec::decode($c, $d) {
+            Ok(decoded) => decoded,
+            Err(e) => return Some(Err(e)),
+        };
         let ($($arg,)*) = decoded;
         let f_result = $s.$fn_name($($arg),*);
-        Some($crate::utils::bincode::serialize(&f_result))
+        Some(Ok($crate::raft::codec::encode($c, &f_result)))
     }};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_cmd {
-    (cmd $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
-        raft_dispatch_fn!($fn_name $s $d( $( $arg : $in_ ),* ))
+    (cmd $fn_name:ident $s: ident $d: ident $c: ident ( $( $arg:ident : $in_:ty ),* )) => {
+        raft_dispatch_fn!($fn_name $s $d $c( $( $arg : $in_ ),* ))
     };
-    ($others:ident $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
+    ($others:ident $fn_name:ident $s: ident $d: ident $c: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_qry {
-    (qry $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
-        raft_dispatch_fn!($fn_name $s $d( $( $arg : $in_ ),* ))
+    (qry $fn_name:ident $s: ident $d: ident $c: ident ( $( $arg:ident : $in_:ty ),* )) => {
+        raft_dispatch_fn!($fn_name $s $d $c( $( $arg : $in_ ),* ))
     };
-    ($others:ident $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
+    ($others:ident $fn_name:ident $s: ident $d: ident $c: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
 }
 
 #[macro_export]
 macro_rules! raft_sm_complete {
     () => {
-        fn fn_dispatch_cmd(&mut self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {self.dispatch_cmd_(fn_id, data)}
-        fn fn_dispatch_qry(&self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {self.dispatch_qry_(fn_id, data)}
+        fn fn_dispatch_cmd(&mut self, fn_id: u64, data: &Vec<u8>, codec: &$crate::raft::codec::Codec)
+            -> Option<$crate::raft::state_machine::master::ExecResult> {self.dispatch_cmd_(fn_id, data, codec)}
+        fn fn_dispatch_qry(&self, fn_id: u64, data: &Vec<u8>, codec: &$crate::raft::codec::Codec)
+            -> Option<$crate::raft::state_machine::master::ExecResult> {self.dispatch_qry_(fn_id, data, codec)}
         fn op_type(&mut self, fn_id: u64) -> Option<$crate::raft::state_machine::OpType> {self.op_type_(fn_id)}
     };
 }
@@ -187,27 +192,27 @@ macro_rules! raft_state_machine {
         pub mod commands {
             use super::*;
             $(
-                #[derive(Serialize, Deserialize, Debug)]
                 pub struct $fn_name {
-                    pub data: Vec<u8>
+                    pub args: ($($in_,)*)
                 }
                 impl $crate::raft::RaftMsg<raft_return_type!($out, $error)> for $fn_name {
-                    fn encode(self) -> (u64, $crate::raft::state_machine::OpType, Vec<u8>) {
+                    fn encode(self, codec: &$crate::raft::codec::Codec)
+                        -> (u64, $crate::raft::state_machine::OpType, Vec<u8>) {
                         (
                             ::bifrost_plugins::hash_ident!($fn_name) as u64,
                             raft_fn_op_type!($smt),
-                            self.data
+                            $crate::raft::codec::encode(codec, &self.args)
                         )
                     }
-                    fn decode_return(data: &Vec<u8>) -> raft_return_type!($out, $error) {
-                        $crate::utils::bincode::deserialize(data)
+                    fn decode_return(codec: &$crate::raft::codec::Codec, data: &Vec<u8>)
+                        -> Result<raft_return_type!($out, $error), $crate::raft::state_machine::master::ExecError> {
+                        $crate::raft::codec::decode(codec, data)
                     }
                 }
                 impl $fn_name {
                     pub fn new($($arg:&$in_),*) -> $fn_name {
-                        let req_data = ($($arg,)*);
                         $fn_name {
-                            data: $crate::utils::bincode::serialize(&req_data)
+                            args: ($($arg.clone(),)*)
                         }
                     }
                 }
@@ -229,10 +234,11 @@ macro_rules! raft_state_machine {
                    }
                 }
            }
-           fn dispatch_cmd_(&mut self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {
+           fn dispatch_cmd_(&mut self, fn_id: u64, data: &Vec<u8>, codec: &$crate::raft::codec::Codec)
+               -> Option<$crate::raft::state_machine::master::ExecResult> {
                match fn_id as usize {
                    $(::bifrost_plugins::hash_ident!($fn_name) => {
-                        raft_dispatch_cmd!($smt $fn_name self data( $( $arg : $in_ ),* ))
+                        raft_dispatch_cmd!($smt $fn_name self data codec( $( $arg : $in_ ),* ))
                    }),*
                    _ => {
                        debug!("Undefined function id: {}", fn_id);
@@ -240,10 +246,11 @@ macro_rules! raft_state_machine {
                    }
                }
            }
-           fn dispatch_qry_(&self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {
+           fn dispatch_qry_(&self, fn_id: u64, data: &Vec<u8>, codec: &$crate::raft::codec::Codec)
+               -> Option<$crate::raft::state_machine::master::ExecResult> {
                match fn_id as usize {
                    $(::bifrost_plugins::hash_ident!($fn_name) => {
-                        raft_dispatch_qry!($smt $fn_name self data( $( $arg : $in_ ),* ))
+                        raft_dispatch_qry!($smt $fn_name self data codec( $( $arg : $in_ ),* ))
                    }),*
                    _ => {
                        debug!("Undefined function id: {}", fn_id);
@@ -1,11 +1,13 @@
 use self::configs::{Configures, RaftMember, CONFIG_SM_ID};
 use super::super::*;
 use super::*;
-use std::collections::HashMap;
+use raft::codec::Codec;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
 use std::fmt::Formatter;
+use std::sync::Arc;
 use utils::bincode;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -17,6 +19,31 @@ pub enum ExecError {
     NotCommitted,
     Unknown,
     TooManyRetry,
+    Throttled,
+    ExhaustedRetries { attempts: Vec<RetryReason> },
+    TermChanged,
+    NotLeader(u64), // the member asked is not the leader, with its leader hint
+    PayloadTooLarge { size: usize, limit: usize },
+    Timeout,
+    DiscoveryPaused, // the command needed another leader while discovery was paused
+    Rpc(String),     // the request did not get through to the raft service, with the rpc error
+    ServerError,     // the service got the request and failed it without a reason
+    Overloaded,      // too many calls in flight and waiting for a slot on this client
+    Decode(String),  // the codec could not read the payload
+    NotQuery,        // only queries can be answered from the client cache
+}
+
+/// Why the client had to try a request again, in the order it happened
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RetryReason {
+    NotLeader,
+    NotCommitted,
+    Throttled,
+    LeftBehind,
+    NoLeader, // no client for the leader, cluster info has to be refreshed
+    NetworkError,
+    Timeout,
+    ServerError, // the service itself returned an error
 }
 
 pub enum RegisterResult {
@@ -31,12 +58,18 @@ pub type SubStateMachine = Box<StateMachineCtl>;
 pub type SnapshotDataItem = (u64, Vec<u8>);
 pub type SnapshotDataItems = Vec<SnapshotDataItem>;
 
+// idempotency tokens remembered with their results, the oldest ones are forgotten first
+const MAX_APPLIED_TOKENS: usize = 4096;
+
 raft_state_machine! {}
 
 pub struct MasterStateMachine {
     subs: HashMap<u64, SubStateMachine>,
     snapshots: HashMap<u64, Vec<u8>>,
     pub configs: Configures,
+    applied_tokens: HashMap<u64, ExecResult>,
+    token_order: VecDeque<u64>,
+    codec: Arc<Codec>,
 }
 
 impl StateMachineCmds for MasterStateMachine {}
@@ -55,31 +88,51 @@ impl StateMachineCtl for MasterStateMachine {
             }
         }
         sms.push((self.configs.id(), self.configs.snapshot().unwrap()));
+        // the applied tokens go under the id of the master, oldest first
+        let tokens: Vec<(u64, &ExecResult)> = self
+            .token_order
+            .iter()
+            .map(|token| (*token, &self.applied_tokens[token]))
+            .collect();
+        sms.push((self.id(), bincode::serialize(&tokens)));
         let data = bincode::serialize(&sms);
         Some(data)
     }
     fn recover(&mut self, data: Vec<u8>) {
         let mut sms: SnapshotDataItems = bincode::deserialize(&data);
         for (sm_id, snapshot) in sms {
-            self.snapshots.insert(sm_id, snapshot);
+            if sm_id == self.id() {
+                let tokens: Vec<(u64, ExecResult)> = bincode::deserialize(&snapshot);
+                self.applied_tokens.clear();
+                self.token_order.clear();
+                for (token, res) in tokens {
+                    self.applied_tokens.insert(token, res);
+                    self.token_order.push_back(token);
+                }
+            } else {
+                self.snapshots.insert(sm_id, snapshot);
+            }
         }
     }
 }
 
-fn parse_output(r: Option<Vec<u8>>) -> ExecResult {
-    if let Some(d) = r {
-        Ok(d)
+fn parse_output(r: Option<ExecResult>) -> ExecResult {
+    if let Some(res) = r {
+        res
     } else {
         Err(ExecError::FnNotFound)
     }
 }
 
 impl MasterStateMachine {
-    pub fn new(service_id: u64) -> MasterStateMachine {
+    pub fn new(service_id: u64, codec: Arc<Codec>) -> MasterStateMachine {
         let mut msm = MasterStateMachine {
             subs: HashMap::new(),
             snapshots: HashMap::new(),
             configs: Configures::new(service_id),
+            applied_tokens: HashMap::new(),
+            token_order: VecDeque::new(),
+            codec,
         };
         msm
     }
@@ -104,11 +157,33 @@ impl MasterStateMachine {
     }
 
     pub fn commit_cmd(&mut self, entry: &LogEntry) -> ExecResult {
+        // every member applies the same log, so all of them skip the same repeats.
+        // The applied tokens are in the snapshot, members recovering from it skip them too
+        if let Some(token) = entry.idempotency_token {
+            if let Some(res) = self.applied_tokens.get(&token) {
+                return res.clone();
+            }
+        }
+        let res = self.dispatch_cmd(entry);
+        if let Some(token) = entry.idempotency_token {
+            self.applied_tokens.insert(token, res.clone());
+            self.token_order.push_back(token);
+            if self.token_order.len() > MAX_APPLIED_TOKENS {
+                let oldest = self.token_order.pop_front().unwrap();
+                self.applied_tokens.remove(&oldest);
+            }
+        }
+        res
+    }
+    fn dispatch_cmd(&mut self, entry: &LogEntry) -> ExecResult {
+        let codec = &*self.codec;
         match entry.sm_id {
-            CONFIG_SM_ID => parse_output(self.configs.fn_dispatch_cmd(entry.fn_id, &entry.data)),
+            CONFIG_SM_ID => {
+                parse_output(self.configs.fn_dispatch_cmd(entry.fn_id, &entry.data, codec))
+            }
             _ => {
                 if let Some(sm) = self.subs.get_mut(&entry.sm_id) {
-                    parse_output(sm.as_mut().fn_dispatch_cmd(entry.fn_id, &entry.data))
+                    parse_output(sm.as_mut().fn_dispatch_cmd(entry.fn_id, &entry.data, codec))
                 } else {
                     Err(ExecError::SmNotFound)
                 }
@@ -116,11 +191,14 @@ impl MasterStateMachine {
         }
     }
     pub fn exec_qry(&self, entry: &LogEntry) -> ExecResult {
+        let codec = &*self.codec;
         match entry.sm_id {
-            CONFIG_SM_ID => parse_output(self.configs.fn_dispatch_qry(entry.fn_id, &entry.data)),
+            CONFIG_SM_ID => {
+                parse_output(self.configs.fn_dispatch_qry(entry.fn_id, &entry.data, codec))
+            }
             _ => {
                 if let Some(sm) = self.subs.get(&entry.sm_id) {
-                    parse_output(sm.fn_dispatch_qry(entry.fn_id, &entry.data))
+                    parse_output(sm.fn_dispatch_qry(entry.fn_id, &entry.data, codec))
                 } else {
                     Err(ExecError::SmNotFound)
                 }
@@ -1,3 +1,5 @@
+use self::master::ExecResult;
+use raft::codec::Codec;
 use std::any::Any;
 
 pub enum Storage {
@@ -5,6 +7,7 @@ pub enum Storage {
     DISK(String),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpType {
     COMMAND,
     QUERY,
@@ -15,8 +18,8 @@ pub trait StateMachineCtl: Sync + Send + Any {
     fn id(&self) -> u64;
     fn snapshot(&self) -> Option<Vec<u8>>;
     fn recover(&mut self, data: Vec<u8>);
-    fn fn_dispatch_qry(&self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>>;
-    fn fn_dispatch_cmd(&mut self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>>;
+    fn fn_dispatch_qry(&self, fn_id: u64, data: &Vec<u8>, codec: &Codec) -> Option<ExecResult>;
+    fn fn_dispatch_cmd(&mut self, fn_id: u64, data: &Vec<u8>, codec: &Codec) -> Option<ExecResult>;
     fn op_type(&mut self, fn_id: u64) -> Option<OpType>;
 }
 
@@ -10,6 +10,7 @@ use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use tcp;
+use tokio_timer::Timer;
 use utils::async_locks::{Mutex, RwLock};
 use utils::time;
 use utils::u8vec::*;
@@ -202,6 +203,41 @@ impl ClientPool {
         self.get_by_id(server_id, move |_| addr_clone)
     }
 
+    /// Like `get`, but gives up on a server that does not accept the connection within `timeout`.
+    /// The pool is not locked while connecting, so a slow server only holds up its own callers.
+    pub fn get_with_connect_timeout(
+        &self,
+        addr: &String,
+        timeout: Duration,
+    ) -> Result<Arc<RPCClient>, io::Error> {
+        let server_id = hash_str(addr);
+        if let Some(client) = self.clients.lock().get(&server_id) {
+            return Ok(client.clone());
+        }
+        let deadline = Timer::default()
+            .sleep(timeout)
+            .then(|_| -> Result<Arc<RPCClient>, io::Error> {
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            });
+        let client = RPCClient::new_async(addr.clone())
+            .select(deadline)
+            .map(|(client, _)| client)
+            .map_err(|(e, _)| e)
+            .wait()?;
+        let mut clients = self.clients.lock();
+        Ok(clients.entry(server_id).or_insert(client).clone())
+    }
+
+    /// Forget the connection to a server, it is closed once its last user lets go of it.
+    /// The next `get` for the server connects again.
+    pub fn remove(&self, server_id: u64) -> Option<Arc<RPCClient>> {
+        self.clients.lock().remove(&server_id)
+    }
+
+    pub fn contains(&self, server_id: u64) -> bool {
+        self.clients.lock().contains_key(&server_id)
+    }
+
     pub fn get_by_id<F>(&self, server_id: u64, addr_fn: F) -> Result<Arc<RPCClient>, io::Error>
     where
         F: FnOnce(u64) -> String,
@@ -49,6 +49,16 @@ where
     POOL.spawn(SendFuture { inner: future })
 }
 
+/// Run the future on the pool without waiting for it
+pub fn spawn<F>(future: F)
+where
+    F: Future + 'static,
+    F::Item: Send + 'static,
+    F::Error: Send + 'static,
+{
+    POOL.spawn(SendFuture { inner: future }).forget()
+}
+
 pub fn wait<F>(future: F) -> Result<F::Item, F::Error>
 where
     F: Future + 'static,
@@ -1,7 +1,10 @@
-use bifrost_hasher::hash_str;
-use parking_lot::RwLock;
-use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use bifrost_hasher::{hash_bytes, hash_str};
+use parking_lot::{Mutex, RwLock};
+use std::cmp::{min, Ordering};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::iter::{FromIterator, Sum};
+use std::ops::{BitOr, BitOrAssign};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum Relation {
@@ -11,64 +14,188 @@ pub enum Relation {
     Concurrent,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq)]
-pub struct VectorClock<S: Ord + Eq + Copy> {
-    map: BTreeMap<S, u64>,
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ClockError {
+    DuplicateServer,
+    UnknownServer, // not among the servers allowed to advance the clock
 }
 
-impl<S: Eq + Copy + Ord> PartialOrd for VectorClock<S> {
-    fn partial_cmp(&self, other: &VectorClock<S>) -> Option<Ordering> {
-        let rel = self.relation(other);
-        match rel {
-            Relation::Before => Some(Ordering::Less),
-            Relation::After => Some(Ordering::Greater),
-            Relation::Equal => Some(Ordering::Equal),
-            Relation::Concurrent => None,
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    Truncated,
+    VarintOverflow,
+    TrailingBytes,
+    InvalidTag,
+    UnsupportedVersion(u8),
+}
+
+/// What `merge_all_from_bytes` does with a clock that fails to decode
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DecodePolicy {
+    /// Ignore it and merge the others
+    Skip,
+    /// Merge nothing and return the error
+    Fail,
+}
+
+/// Version byte written in front of every encoded clock
+pub const FORMAT_VERSION: u8 = 1;
+
+type Decoder = fn(&[u8]) -> Result<VectorClock<u64>, DecodeError>;
+
+// every version that can still be read, keep old entries when the format changes
+static DECODERS: &'static [(u8, Decoder)] = &[(1, decode_v1)];
+
+/// Integer types usable as clock counters, implemented for the unsigned integers.
+/// Other types only need `zero`, `one` and `checked_add`.
+pub trait Counter: Ord + Eq + Copy {
+    fn zero() -> Self;
+    fn one() -> Self;
+    fn checked_add(self, other: Self) -> Option<Self>;
+    /// Next value, saturating at the type's maximum
+    fn incr(self) -> Self {
+        self.checked_add(Self::one()).unwrap_or(self)
+    }
+}
+
+macro_rules! impl_counter {
+    ($($t:ty),*) => {$(
+        impl Counter for $t {
+            fn zero() -> $t {
+                0
+            }
+            fn one() -> $t {
+                1
+            }
+            fn checked_add(self, other: $t) -> Option<$t> {
+                <$t>::checked_add(self, other)
+            }
+        }
+    )*};
+}
+
+impl_counter!(u8, u16, u32, u64, u128);
+
+/// Decides the merged counter of each server, see `VectorClock::merge_with_policy`.
+/// `mine` is zero when the server is not in the local clock yet.
+pub trait MergePolicy<S, C = u64> {
+    fn merge_counter(&self, server: &S, mine: C, theirs: C) -> C;
+}
+
+/// Plain `merge_with` behaviour, the larger counter wins
+pub struct MaxMerge;
+
+impl<S, C: Counter> MergePolicy<S, C> for MaxMerge {
+    fn merge_counter(&self, _server: &S, mine: C, theirs: C) -> C {
+        if mine < theirs {
+            theirs
+        } else {
+            mine
         }
     }
 }
 
-impl<S: Eq + Copy + Ord> Ord for VectorClock<S> {
+const HYBRID_LOGICAL_RANGE: u64 = 1 << 16;
+
+const TAG_ALPHABET: &'static [u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq)]
+pub struct VectorClock<S: Ord + Eq + Copy, C: Counter = u64> {
+    map: BTreeMap<S, C>,
+}
+
+/// Same as `Ord`, use `relation` for the causal order
+impl<S: Eq + Copy + Ord, C: Counter> PartialOrd for VectorClock<S, C> {
+    fn partial_cmp(&self, other: &VectorClock<S, C>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Counters compared server by server over the servers of both clocks, absent ones counting
+/// as zero, so only equal clocks compare equal in ordered collections. The first server that
+/// differs is lower in a clock that happened before the other, so the order is consistent
+/// with the causal order.
+impl<S: Eq + Copy + Ord, C: Counter> Ord for VectorClock<S, C> {
     fn cmp(&self, other: &Self) -> Ordering {
-        let rel = self.relation(other);
-        match rel {
-            Relation::Before => Ordering::Less,
-            Relation::After => Ordering::Greater,
-            _ => Ordering::Equal, // not justified, but sufficient for BTreeSet data structure
+        cmp_counters(self, other)
+    }
+}
+
+/// Zero counters are left out, as an explicit zero equals an absent server.
+impl<S: Eq + Copy + Ord + Hash, C: Counter + Hash> Hash for VectorClock<S, C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for (server, counter) in self.iter_nonzero() {
+            server.hash(state);
+            counter.hash(state);
         }
     }
 }
 
-impl<S: Eq + Copy + Ord> PartialEq for VectorClock<S> {
-    fn eq(&self, other: &VectorClock<S>) -> bool {
+impl<S: Eq + Copy + Ord, C: Counter> PartialEq for VectorClock<S, C> {
+    fn eq(&self, other: &VectorClock<S, C>) -> bool {
         let rel = self.relation(other);
         rel == Relation::Equal
     }
 }
 
-impl<S: Ord + Eq + Copy> VectorClock<S> {
-    pub fn new() -> VectorClock<S> {
+impl<S: Ord + Eq + Copy, C: Counter> Default for VectorClock<S, C> {
+    fn default() -> VectorClock<S, C> {
+        VectorClock::new()
+    }
+}
+
+impl<S: Ord + Eq + Copy, C: Counter> VectorClock<S, C> {
+    pub fn new() -> VectorClock<S, C> {
         VectorClock {
             map: BTreeMap::new(),
         }
     }
 
-    pub fn inc(&mut self, server: S) -> VectorClock<S> {
-        *self.map.entry(server).or_insert(0) += 1;
+    // strict import, the same server must not appear twice
+    pub fn from_pairs_checked(pairs: Vec<(S, C)>) -> Result<VectorClock<S, C>, ClockError> {
+        let mut map = BTreeMap::new();
+        for (server, counter) in pairs {
+            if map.insert(server, counter).is_some() {
+                return Err(ClockError::DuplicateServer);
+            }
+        }
+        Ok(VectorClock { map })
+    }
+
+    /// Advance the counter of `server` and return the new clock.
+    /// A counter at its maximum stays there instead of wrapping around, which would make the
+    /// clock look older than it is. Later events at that server can no longer be ordered.
+    pub fn inc(&mut self, server: S) -> VectorClock<S, C> {
+        self.inc_in_place(server);
         self.clone()
     }
+    /// `inc` without cloning the clock for the result
+    pub fn inc_in_place(&mut self, server: S) {
+        let counter = self.map.entry(server).or_insert(C::zero());
+        *counter = counter.incr();
+    }
+    /// `inc` for servers in `allowed` only, returns the new counter of `server`.
+    /// The clock is left untouched for any other server.
+    pub fn try_inc(&mut self, server: S, allowed: &BTreeSet<S>) -> Result<C, ClockError> {
+        if !allowed.contains(&server) {
+            return Err(ClockError::UnknownServer);
+        }
+        self.inc_in_place(server);
+        Ok(self.map[&server])
+    }
 
-    pub fn happened_before(&self, clock_b: &VectorClock<S>) -> bool {
+    pub fn happened_before(&self, clock_b: &VectorClock<S, C>) -> bool {
         let mut a_lt_b = false;
         for (server, ai) in self.map.iter() {
-            let bi = *clock_b.map.get(server).unwrap_or(&0);
+            let bi = *clock_b.map.get(server).unwrap_or(&C::zero());
             if *ai > bi {
                 return false;
             }
             a_lt_b = a_lt_b || *ai < bi;
         }
         for (server, bi) in clock_b.map.iter() {
-            let ai = *self.map.get(server).unwrap_or(&0);
+            let ai = *self.map.get(server).unwrap_or(&C::zero());
             if ai > *bi {
                 return false;
             }
@@ -76,72 +203,852 @@ impl<S: Ord + Eq + Copy> VectorClock<S> {
         }
         return a_lt_b;
     }
-    pub fn equals(&self, clock_b: &VectorClock<S>) -> bool {
-        let b_keys: Vec<_> = clock_b.map.keys().collect();
-        let self_keys: Vec<_> = self.map.keys().collect();
-        if b_keys != self_keys {
-            return false;
+    /// Same counters for every server, an explicit zero equals an absent server
+    pub fn equals(&self, clock_b: &VectorClock<S, C>) -> bool {
+        self.iter_nonzero().eq(clock_b.iter_nonzero())
+    }
+    /// Single pass over both clocks, stops at the first pair of servers showing concurrency
+    pub fn relation(&self, clock_b: &VectorClock<S, C>) -> Relation {
+        relation_of(self.map.iter(), clock_b.map.iter())
+    }
+    /// `relation` to each clock of `others`, in the same order.
+    /// Each comparison is already a single pass, there is nothing shared between them to reuse.
+    pub fn relations_against<'a, I>(&self, others: I) -> Vec<Relation>
+    where
+        I: IntoIterator<Item = &'a VectorClock<S, C>>,
+        S: 'a,
+        C: 'a,
+    {
+        others
+            .into_iter()
+            .map(|other| self.relation(other))
+            .collect()
+    }
+    /// Like `relation`, but a server missing from a clock ranks below every counter, zero included.
+    /// Servers present in only one clock with a counter above zero make that clock ahead,
+    /// as with `relation`. An explicit zero entry also makes its clock ahead of a clock lacking
+    /// the server, where `relation` treats the two the same.
+    /// Servers absent from both clocks do not take part.
+    pub fn relation_strict(&self, clock_b: &VectorClock<S, C>) -> Relation {
+        let mut behind = false;
+        let mut ahead = false;
+        for server in self.map.keys().chain(clock_b.map.keys()) {
+            match self.map.get(server).cmp(&clock_b.map.get(server)) {
+                Ordering::Less => behind = true,
+                Ordering::Greater => ahead = true,
+                Ordering::Equal => {}
+            }
         }
-        for (server, ac) in self.map.iter() {
-            let bc: u64 = {
-                match clock_b.map.get(server) {
-                    Some(v) => *v,
-                    None => {
-                        return false;
-                    }
+        match (behind, ahead) {
+            (false, false) => Relation::Equal,
+            (true, false) => Relation::Before,
+            (false, true) => Relation::After,
+            (true, true) => Relation::Concurrent,
+        }
+    }
+    /// Least upper bound of all `clocks`, the largest counter of every server
+    pub fn join(clocks: &[VectorClock<S, C>]) -> VectorClock<S, C> {
+        let mut map = BTreeMap::new();
+        for clock in clocks {
+            for (server, counter) in clock.iter_nonzero() {
+                let joined = map.entry(*server).or_insert(*counter);
+                if *joined < *counter {
+                    *joined = *counter;
                 }
-            };
-            if *ac != bc {
-                return false;
             }
         }
-        return true;
+        VectorClock { map }
+    }
+    /// Merging with an equal clock is a no-op.
+    /// Zero counters of unknown servers are not copied over.
+    pub fn merge_with(&mut self, clock_b: &VectorClock<S, C>) {
+        self.merge_with_changed(clock_b);
+    }
+    /// `merge_with`, returns true when at least one counter was raised
+    pub fn merge_with_changed(&mut self, clock_b: &VectorClock<S, C>) -> bool {
+        // merge_with is used to update counter for other servers (also learn from it)
+        let mut changed = false;
+        for (server, bc) in clock_b.iter_nonzero() {
+            let mut ba = self.map.entry(*server).or_insert(C::zero());
+            if *ba < *bc {
+                *ba = *bc;
+                changed = true;
+            }
+        }
+        changed
     }
-    pub fn relation(&self, clock_b: &VectorClock<S>) -> Relation {
-        if self.equals(clock_b) {
-            return Relation::Equal;
+    /// `merge_with` for a clock in `my_epoch`, counters from an older epoch are ignored.
+    /// Returns false when `other` was left out for its epoch.
+    pub fn merge_with_epoch(&mut self, other: &VectorClockEpoched<S, C>, my_epoch: u64) -> bool {
+        if other.epoch < my_epoch {
+            return false;
         }
-        if self.happened_before(clock_b) {
-            return Relation::Before;
+        self.merge_with(&other.clock);
+        true
+    }
+    /// `merge_with`, also returns the relation before the merge and the servers that advanced
+    pub fn merge_reporting(&mut self, clock_b: &VectorClock<S, C>) -> (Relation, Vec<S>) {
+        let relation = self.relation(clock_b);
+        let mut advanced = Vec::new();
+        for (server, bc) in clock_b.iter_nonzero() {
+            let mut ba = self.map.entry(*server).or_insert(C::zero());
+            if *ba < *bc {
+                *ba = *bc;
+                advanced.push(*server);
+            }
         }
-        if clock_b.happened_before(self) {
-            return Relation::After;
+        (relation, advanced)
+    }
+    /// The entries where `self` is ahead of `base`, with the counters of `self`.
+    /// Merging the result into `base` gives the join of both clocks.
+    pub fn delta_since(&self, base: &VectorClock<S, C>) -> VectorClock<S, C> {
+        VectorClock {
+            map: self
+                .map
+                .iter()
+                .filter(|&(server, counter)| *counter > *base.map.get(server).unwrap_or(&C::zero()))
+                .map(|(server, counter)| (*server, *counter))
+                .collect(),
         }
-        return Relation::Concurrent;
     }
-    pub fn merge_with(&mut self, clock_b: &VectorClock<S>) {
-        // merge_with is used to update counter for other servers (also learn from it)
+    /// Drop every entry, as if just created with `new`
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+    /// Drop the entries with a zero counter, returns how many were removed
+    pub fn normalize(&mut self) -> usize {
+        let zeros: Vec<S> = self
+            .map
+            .iter()
+            .filter(|&(_, counter)| *counter == C::zero())
+            .map(|(server, _)| *server)
+            .collect();
+        for server in &zeros {
+            self.map.remove(server);
+        }
+        zeros.len()
+    }
+    /// Merge with every counter decided by `policy`, for untrusted peers
+    pub fn merge_with_policy<P>(&mut self, clock_b: &VectorClock<S, C>, policy: &P)
+    where
+        P: MergePolicy<S, C>,
+    {
         for (server, bc) in clock_b.map.iter() {
-            let mut ba = self.map.entry(*server).or_insert(0);
-            if *ba < *bc {
-                *ba = *bc
+            let mine = *self.map.get(server).unwrap_or(&C::zero());
+            let merged = policy.merge_counter(server, mine, *bc);
+            // no zero entries for servers the clock did not have, as with merge_with
+            if merged != C::zero() || self.map.contains_key(server) {
+                self.map.insert(*server, merged);
             }
         }
     }
-    pub fn learn_from(&mut self, clock_b: &VectorClock<S>) {
+    /// Merge, then keep only the `max_entries` servers with the highest `recency`.
+    /// Ties are broken by server id. Returns the dropped entries.
+    pub fn merge_bounded_by<F>(
+        &mut self,
+        clock_b: &VectorClock<S, C>,
+        max_entries: usize,
+        recency: F,
+    ) -> Vec<(S, C)>
+    where
+        F: Fn(&S) -> u64,
+    {
+        self.merge_with(clock_b);
+        if self.map.len() <= max_entries {
+            return Vec::new();
+        }
+        let mut servers: Vec<S> = self.map.keys().cloned().collect();
+        servers.sort_by(|a, b| recency(b).cmp(&recency(a)).then(a.cmp(b)));
+        servers
+            .into_iter()
+            .skip(max_entries)
+            .map(|server| (server, self.map.remove(&server).unwrap()))
+            .collect()
+    }
+    /// Keep only the `n` servers with the highest counters, ties broken by server id.
+    /// Returns the dropped entries, highest counter first.
+    pub fn truncate_to_top_n(&mut self, n: usize) -> Vec<(S, C)> {
+        if self.map.len() <= n {
+            return Vec::new();
+        }
+        let mut entries: Vec<(S, C)> = self.map.iter().map(|(s, c)| (*s, *c)).collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let dropped = entries.split_off(n);
+        for &(server, _) in dropped.iter() {
+            self.map.remove(&server);
+        }
+        dropped
+    }
+    pub fn learn_from(&mut self, clock_b: &VectorClock<S, C>) {
         // learn_from only insert missing servers into the clock
         for (server, bc) in clock_b.map.iter() {
             self.map.entry(*server).or_insert(*bc);
         }
     }
+    /// Drop the entries every replica has already acknowledged, that is entries at or below
+    /// the element-wise minimum of `replica_clocks`. Returns the number of entries removed.
+    pub fn gc_below_frontier(&mut self, replica_clocks: &[VectorClock<S, C>]) -> usize {
+        if replica_clocks.is_empty() {
+            return 0;
+        }
+        let stable = |server: &S| {
+            replica_clocks
+                .iter()
+                .map(|clock| *clock.map.get(server).unwrap_or(&C::zero()))
+                .min()
+                .unwrap()
+        };
+        let acknowledged: Vec<S> = self
+            .map
+            .iter()
+            .filter(|&(server, counter)| *counter <= stable(server))
+            .map(|(server, _)| *server)
+            .collect();
+        for server in acknowledged.iter() {
+            self.map.remove(server);
+        }
+        acknowledged.len()
+    }
+    /// Both clocks expanded to the union of their servers, missing entries filled with zeros
+    pub fn align_with(&self, other: &VectorClock<S, C>) -> (VectorClock<S, C>, VectorClock<S, C>) {
+        let mut a = self.clone();
+        let mut b = other.clone();
+        for server in self.map.keys() {
+            b.map.entry(*server).or_insert(C::zero());
+        }
+        for server in other.map.keys() {
+            a.map.entry(*server).or_insert(C::zero());
+        }
+        (a, b)
+    }
+    /// A clock with only the entries of the given servers
+    pub fn project(&self, servers: &BTreeSet<S>) -> VectorClock<S, C> {
+        VectorClock {
+            map: self
+                .map
+                .iter()
+                .filter(|&(server, _)| servers.contains(server))
+                .map(|(server, counter)| (*server, *counter))
+                .collect(),
+        }
+    }
+    /// All entries in server order, zero counters included
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a S, &'a C)> + 'a {
+        self.map.iter()
+    }
+    /// Number of servers in the clock, zero counters included
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+    /// Entries with a non-zero counter in server order, without touching the clock.
+    /// Zero counters record no events, export paths can leave them out.
+    pub fn iter_nonzero<'a>(&'a self) -> impl Iterator<Item = (&'a S, &'a C)> + 'a {
+        self.map
+            .iter()
+            .filter(|&(_, counter)| *counter != C::zero())
+    }
+    /// Drop the entries of servers not in `live`, returns how many were removed.
+    /// Relations given by the live servers are unchanged, but clocks that were only concurrent
+    /// because of a dead server become ordered, so prune every clock with the same set.
+    pub fn prune(&mut self, live: &BTreeSet<S>) -> usize {
+        let dead: Vec<S> = self
+            .map
+            .keys()
+            .filter(|server| !live.contains(server))
+            .cloned()
+            .collect();
+        for server in &dead {
+            self.map.remove(server);
+        }
+        dead.len()
+    }
+    /// True unless the two clocks are concurrent, equal clocks are ordered
+    pub fn is_ordered_with(&self, other: &VectorClock<S, C>) -> bool {
+        self.relation(other) != Relation::Concurrent
+    }
+    /// True when `self` is equal to or after `other`
+    pub fn dominates(&self, other: &VectorClock<S, C>) -> bool {
+        match self.relation(other) {
+            Relation::Equal | Relation::After => true,
+            Relation::Before | Relation::Concurrent => false,
+        }
+    }
+    /// True when `self` may follow `prior` in a replayed log, it is equal to or after `prior`.
+    /// `delta_since(prior)` then gives the entries the step advanced.
+    pub fn is_extension_of(&self, prior: &VectorClock<S, C>) -> bool {
+        self.dominates(prior)
+    }
+    /// True when `self` is after `other`
+    pub fn strictly_dominates(&self, other: &VectorClock<S, C>) -> bool {
+        self.relation(other) == Relation::After
+    }
+    /// True when `self` does not strictly dominate `other`: before, equal or concurrent
+    pub fn not_after(&self, other: &VectorClock<S, C>) -> bool {
+        !other.happened_before(self)
+    }
+    /// Number of servers whose counters differ, absent servers count as zero
+    pub fn diff_count(&self, other: &VectorClock<S, C>) -> usize {
+        let zero = C::zero();
+        let differ_in_self = self
+            .map
+            .iter()
+            .filter(|&(server, counter)| counter != other.map.get(server).unwrap_or(&zero))
+            .count();
+        let only_in_other = other
+            .map
+            .iter()
+            .filter(|&(server, counter)| !self.map.contains_key(server) && *counter != zero)
+            .count();
+        differ_in_self + only_in_other
+    }
+    /// Compare the counters of a single server, absent servers count as zero
+    pub fn compare_component(&self, other: &VectorClock<S, C>, server: &S) -> Ordering {
+        let a = *self.map.get(server).unwrap_or(&C::zero());
+        let b = *other.map.get(server).unwrap_or(&C::zero());
+        a.cmp(&b)
+    }
+    /// Siblings are concurrent versions that both descend from (or equal) the common ancestor
+    pub fn is_sibling_of(&self, other: &VectorClock<S, C>, common: &VectorClock<S, C>) -> bool {
+        let descends = |clock: &VectorClock<S, C>| match clock.relation(common) {
+            Relation::After | Relation::Equal => true,
+            _ => false,
+        };
+        self.relation(other) == Relation::Concurrent && descends(self) && descends(other)
+    }
+}
+
+/// `&a | &b` is the join of the two clocks, same as merging them
+impl<'a, 'b, S, C> BitOr<&'b VectorClock<S, C>> for &'a VectorClock<S, C>
+where
+    S: Ord + Eq + Copy,
+    C: Counter,
+{
+    type Output = VectorClock<S, C>;
+    fn bitor(self, other: &'b VectorClock<S, C>) -> VectorClock<S, C> {
+        let mut joined = self.clone();
+        joined.merge_with(other);
+        joined
+    }
+}
+
+impl<'a, S, C> BitOrAssign<&'a VectorClock<S, C>> for VectorClock<S, C>
+where
+    S: Ord + Eq + Copy,
+    C: Counter,
+{
+    fn bitor_assign(&mut self, other: &'a VectorClock<S, C>) {
+        self.merge_with(other)
+    }
+}
+
+/// Summing clocks joins them, an empty iterator gives an empty clock
+impl<S: Ord + Eq + Copy, C: Counter> Sum for VectorClock<S, C> {
+    fn sum<I: Iterator<Item = VectorClock<S, C>>>(iter: I) -> VectorClock<S, C> {
+        let mut joined = VectorClock::new();
+        for clock in iter {
+            joined.merge_with(&clock);
+        }
+        joined
+    }
+}
+
+/// Same as merging the pairs one by one, a server given twice keeps the larger counter
+impl<S: Ord + Eq + Copy, C: Counter> Extend<(S, C)> for VectorClock<S, C> {
+    fn extend<I: IntoIterator<Item = (S, C)>>(&mut self, iter: I) {
+        for (server, counter) in iter {
+            if counter == C::zero() {
+                continue;
+            }
+            let mut current = self.map.entry(server).or_insert(C::zero());
+            if *current < counter {
+                *current = counter;
+            }
+        }
+    }
+}
+
+impl<S: Ord + Eq + Copy, C: Counter> FromIterator<(S, C)> for VectorClock<S, C> {
+    fn from_iter<I: IntoIterator<Item = (S, C)>>(iter: I) -> VectorClock<S, C> {
+        let mut clock = VectorClock::new();
+        clock.extend(iter);
+        clock
+    }
+}
+
+impl<S: Ord + Eq + Copy> VectorClock<S> {
+    /// Best-effort bridge to hybrid logical clocks, this is not a true HLC.
+    /// The largest physical timestamp of the servers in the clock, as reported by `phys`, takes
+    /// the high bits and the largest counter takes the low 16 bits, saturating at `0xffff`.
+    /// The estimate never decreases while counters and timestamps only advance.
+    pub fn hybrid_estimate<F: Fn(&S) -> u64>(&self, phys: F) -> u64 {
+        let physical = self.map.keys().map(phys).max().unwrap_or(0);
+        let logical = self.map.values().cloned().max().unwrap_or(0);
+        physical.saturating_mul(HYBRID_LOGICAL_RANGE) | min(logical, HYBRID_LOGICAL_RANGE - 1)
+    }
+    /// Add the counters of `clock_b` to these, for additive CRDTs only, causal clocks merge with
+    /// `merge_with`. Sums saturate at `u64::MAX` instead of wrapping.
+    pub fn merge_saturating_sum(&mut self, clock_b: &VectorClock<S>) {
+        for (server, bc) in clock_b.iter_nonzero() {
+            let mut ba = self.map.entry(*server).or_insert(0);
+            *ba = ba.saturating_add(*bc);
+        }
+    }
+}
+
+/// Compares one clock against many others.
+/// The clock is normalized once into a sorted slice without zero counters, so each comparison
+/// skips the zeros of both clocks and walks a slice rather than the map.
+pub struct ClockComparator<S: Ord + Eq + Copy, C: Counter = u64> {
+    entries: Vec<(S, C)>,
+}
+
+impl<S: Ord + Eq + Copy, C: Counter> ClockComparator<S, C> {
+    pub fn new(clock: &VectorClock<S, C>) -> ClockComparator<S, C> {
+        ClockComparator {
+            entries: clock.iter_nonzero().map(|(s, c)| (*s, *c)).collect(),
+        }
+    }
+
+    /// Same result as `clock.relation(other)`, zero counters count as absent in both
+    pub fn relation_to(&self, other: &VectorClock<S, C>) -> Relation {
+        relation_of(
+            self.entries
+                .iter()
+                .map(|&(ref server, ref counter)| (server, counter)),
+            other.iter_nonzero(),
+        )
+    }
+}
+
+/// Candidates that causally precede `target`, in their original order
+pub fn ancestors_of<'a, S: Ord + Eq + Copy, C: Counter>(
+    target: &VectorClock<S, C>,
+    candidates: &'a [VectorClock<S, C>],
+) -> Vec<&'a VectorClock<S, C>> {
+    candidates
+        .iter()
+        .filter(|candidate| candidate.happened_before(target))
+        .collect()
+}
+
+/// Clocks not dominated by any other clock in the set, concurrent maxima are all kept.
+/// Equal clocks are reported once.
+pub fn frontier<S: Ord + Eq + Copy, C: Counter>(
+    clocks: &[VectorClock<S, C>],
+) -> Vec<VectorClock<S, C>> {
+    let mut result: Vec<VectorClock<S, C>> = Vec::new();
+    for (i, clock) in clocks.iter().enumerate() {
+        let dominated = clocks.iter().any(|other| clock.happened_before(other));
+        let seen = clocks[..i].iter().any(|other| clock.equals(other));
+        if !dominated && !seen {
+            result.push(clock.clone());
+        }
+    }
+    result
+}
+
+/// `[i][j]` is the relation of `clocks[i]` to `clocks[j]`.
+/// Only pairs with `i < j` are compared, `[j][i]` is filled with the inverse relation.
+pub fn relation_matrix<S: Ord + Eq + Copy, C: Counter>(
+    clocks: &[VectorClock<S, C>],
+) -> Vec<Vec<Relation>> {
+    let n = clocks.len();
+    let mut matrix = vec![vec![Relation::Equal; n]; n];
+    for i in 0..n {
+        for j in (i + 1)..n {
+            let relation = clocks[i].relation(&clocks[j]);
+            matrix[j][i] = inverse(&relation);
+            matrix[i][j] = relation;
+        }
+    }
+    matrix
+}
+
+impl VectorClock<u64> {
+    /// Canonical form: the format version, then entry count followed by sorted (server, counter)
+    /// pairs, all as LEB128 varints
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(2 + self.map.len() * 4);
+        data.push(FORMAT_VERSION);
+        write_varint(&mut data, self.map.len() as u64);
+        for (server, counter) in self.map.iter() {
+            write_varint(&mut data, *server);
+            write_varint(&mut data, *counter);
+        }
+        data
+    }
+
+    /// Decode any supported version, dispatching on the leading version byte
+    pub fn from_bytes(data: &[u8]) -> Result<VectorClock<u64>, DecodeError> {
+        let version = match data.first() {
+            Some(version) => *version,
+            None => return Err(DecodeError::Truncated),
+        };
+        match DECODERS.iter().find(|&&(v, _)| v == version) {
+            Some(&(_, decode)) => decode(&data[1..]),
+            None => Err(DecodeError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// Versions `from_bytes` can read
+    pub fn supported_versions() -> Vec<u8> {
+        DECODERS.iter().map(|&(v, _)| v).collect()
+    }
+
+    /// Decode and merge a batch of encoded clocks, returns how many of them advanced this clock.
+    /// Clocks that fail to decode are handled by `policy`.
+    pub fn merge_all_from_bytes<I: IntoIterator<Item = Vec<u8>>>(
+        &mut self,
+        encoded: I,
+        policy: DecodePolicy,
+    ) -> Result<usize, DecodeError> {
+        let mut clocks = Vec::new();
+        for data in encoded {
+            match Self::from_bytes(&data) {
+                Ok(clock) => clocks.push(clock),
+                Err(e) => match policy {
+                    DecodePolicy::Skip => continue,
+                    DecodePolicy::Fail => return Err(e),
+                },
+            }
+        }
+        let mut advanced = 0;
+        for clock in &clocks {
+            if self.merge_with_changed(clock) {
+                advanced += 1;
+            }
+        }
+        Ok(advanced)
+    }
+
+    /// Hash of the canonical bytes with zero entries dropped, usable as an ETag.
+    /// Equal clocks get the same tag and distinct clocks distinct tags with high probability,
+    /// unlike a Lamport style scalar where different clocks share values on purpose.
+    /// Any advance changes the tag, but tags are not ordered.
+    pub fn etag(&self) -> u64 {
+        let mut normalized = self.clone();
+        normalized.normalize();
+        hash_bytes(&normalized.to_bytes())
+    }
+
+    /// Hash of this clock linked to the previous entry of a chain, `prev_hash` is what the
+    /// previous entry got, 0 for the first one. To verify a log, recompute the hashes from the
+    /// first entry on and compare them with the stored ones. A changed, dropped or reordered
+    /// entry changes its hash and every hash after it.
+    /// `hash_bytes` is not cryptographic, this catches corruption, not someone able to rebuild
+    /// the whole chain.
+    pub fn chain_from(&self, prev_hash: u64) -> u64 {
+        let mut normalized = self.clone();
+        normalized.normalize();
+        let mut data = normalized.to_bytes();
+        write_varint(&mut data, prev_hash);
+        hash_bytes(&data)
+    }
+
+    /// URL safe base64 (no padding) of the canonical bytes, for log fields and tracing tags
+    pub fn to_tag(&self) -> String {
+        let data = self.to_bytes();
+        let mut tag = String::with_capacity((data.len() * 4 + 2) / 3);
+        for chunk in data.chunks(3) {
+            let b = [
+                chunk[0],
+                *chunk.get(1).unwrap_or(&0),
+                *chunk.get(2).unwrap_or(&0),
+            ];
+            let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+            for i in 0..chunk.len() + 1 {
+                tag.push(TAG_ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        tag
+    }
+
+    pub fn from_tag(tag: &str) -> Result<VectorClock<u64>, DecodeError> {
+        let mut data = Vec::with_capacity(tag.len() * 3 / 4);
+        for chunk in tag.as_bytes().chunks(4) {
+            if chunk.len() < 2 {
+                return Err(DecodeError::InvalidTag);
+            }
+            let mut n = 0u32;
+            for (i, c) in chunk.iter().enumerate() {
+                let v = match TAG_ALPHABET.iter().position(|a| a == c) {
+                    Some(v) => v as u32,
+                    None => return Err(DecodeError::InvalidTag),
+                };
+                n |= v << (18 - 6 * i);
+            }
+            for i in 0..chunk.len() - 1 {
+                data.push((n >> (16 - 8 * i)) as u8);
+            }
+        }
+        Self::from_bytes(&data)
+    }
+}
+
+fn decode_v1(data: &[u8]) -> Result<VectorClock<u64>, DecodeError> {
+    let mut pos = 0;
+    let len = read_varint(data, &mut pos)?;
+    let mut map = BTreeMap::new();
+    for _ in 0..len {
+        let server = read_varint(data, &mut pos)?;
+        let counter = read_varint(data, &mut pos)?;
+        map.insert(server, counter);
+    }
+    if pos != data.len() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(VectorClock { map })
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = match data.get(*pos) {
+            Some(b) => *b,
+            None => return Err(DecodeError::Truncated),
+        };
+        *pos += 1;
+        if shift >= 64 || (shift == 63 && byte > 1) {
+            return Err(DecodeError::VarintOverflow);
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+// first difference of the counters over the servers of both clocks, absent ones count as zero
+fn cmp_counters<S, C>(a: &VectorClock<S, C>, b: &VectorClock<S, C>) -> Ordering
+where
+    S: Ord + Eq + Copy,
+    C: Counter,
+{
+    let mut a_iter = a.map.iter().peekable();
+    let mut b_iter = b.map.iter().peekable();
+    loop {
+        let a_next = a_iter.peek().map(|&(server, counter)| (*server, *counter));
+        let b_next = b_iter.peek().map(|&(server, counter)| (*server, *counter));
+        let ord = match (a_next, b_next) {
+            (None, None) => return Ordering::Equal,
+            (Some((_, ac)), None) => {
+                a_iter.next();
+                ac.cmp(&C::zero())
+            }
+            (None, Some((_, bc))) => {
+                b_iter.next();
+                C::zero().cmp(&bc)
+            }
+            (Some((a_server, ac)), Some((b_server, bc))) => match a_server.cmp(&b_server) {
+                Ordering::Less => {
+                    a_iter.next();
+                    ac.cmp(&C::zero())
+                }
+                Ordering::Greater => {
+                    b_iter.next();
+                    C::zero().cmp(&bc)
+                }
+                Ordering::Equal => {
+                    a_iter.next();
+                    b_iter.next();
+                    ac.cmp(&bc)
+                }
+            },
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+// relation of b to a given the relation of a to b
+fn inverse(relation: &Relation) -> Relation {
+    match *relation {
+        Relation::Before => Relation::After,
+        Relation::After => Relation::Before,
+        Relation::Equal => Relation::Equal,
+        Relation::Concurrent => Relation::Concurrent,
+    }
+}
+
+// Relation of two clocks given as entries sorted by server, absent servers count as zero.
+fn relation_of<'a, S, C, A, B>(a: A, b: B) -> Relation
+where
+    S: Ord + 'a,
+    C: Counter + 'a,
+    A: Iterator<Item = (&'a S, &'a C)>,
+    B: Iterator<Item = (&'a S, &'a C)>,
+{
+    let mut a_lt_b = false;
+    let mut a_gt_b = false;
+    let mut a_iter = a.peekable();
+    let mut b_iter = b.peekable();
+    loop {
+        // (advance a, advance b, counter in a, counter in b)
+        let (next_a, next_b, ac, bc) = match (a_iter.peek(), b_iter.peek()) {
+            (None, None) => break,
+            (Some(&(_, &ac)), None) => (true, false, ac, C::zero()),
+            (None, Some(&(_, &bc))) => (false, true, C::zero(), bc),
+            (Some(&(sa, &ac)), Some(&(sb, &bc))) => match sa.cmp(sb) {
+                Ordering::Less => (true, false, ac, C::zero()),
+                Ordering::Greater => (false, true, C::zero(), bc),
+                Ordering::Equal => (true, true, ac, bc),
+            },
+        };
+        if next_a {
+            a_iter.next();
+        }
+        if next_b {
+            b_iter.next();
+        }
+        a_lt_b = a_lt_b || ac < bc;
+        a_gt_b = a_gt_b || ac > bc;
+        if a_lt_b && a_gt_b {
+            return Relation::Concurrent;
+        }
+    }
+    if !a_lt_b && !a_gt_b {
+        Relation::Equal
+    } else if a_lt_b && !a_gt_b {
+        Relation::Before
+    } else if a_gt_b && !a_lt_b {
+        Relation::After
+    } else {
+        Relation::Concurrent
+    }
+}
+
+/// Identifies the clock a merge came from, e.g. the hash of a replica address
+pub type SourceId = u64;
+
+/// A clock that remembers, for each server, which source last advanced its counter
+#[derive(Debug, Clone)]
+pub struct VectorClockWithProvenance<S: Ord + Eq + Copy, C: Counter = u64> {
+    clock: VectorClock<S, C>,
+    sources: BTreeMap<S, SourceId>,
+}
+
+impl<S: Ord + Eq + Copy, C: Counter> VectorClockWithProvenance<S, C> {
+    pub fn new() -> VectorClockWithProvenance<S, C> {
+        VectorClockWithProvenance {
+            clock: VectorClock::new(),
+            sources: BTreeMap::new(),
+        }
+    }
+    pub fn clock(&self) -> &VectorClock<S, C> {
+        &self.clock
+    }
+    /// Same as `VectorClock::merge_with`, every counter `other` raises is attributed to `source`
+    pub fn merge_with(&mut self, other: &VectorClock<S, C>, source: SourceId) {
+        for (server, theirs) in other.iter_nonzero() {
+            let mine = self.clock.map.entry(*server).or_insert(C::zero());
+            if *mine < *theirs {
+                *mine = *theirs;
+                self.sources.insert(*server, source);
+            }
+        }
+    }
+    /// The source that last advanced `server`, `None` if no merge ever did
+    pub fn provenance(&self, server: &S) -> Option<SourceId> {
+        self.sources.get(server).cloned()
+    }
+}
+
+/// A clock with the epoch it was taken in, for systems that start counting over every epoch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VectorClockEpoched<S: Ord + Eq + Copy, C: Counter = u64> {
+    pub epoch: u64,
+    pub clock: VectorClock<S, C>,
+}
+
+impl<S: Ord + Eq + Copy, C: Counter> VectorClockEpoched<S, C> {
+    pub fn new(epoch: u64, clock: VectorClock<S, C>) -> VectorClockEpoched<S, C> {
+        VectorClockEpoched { epoch, clock }
+    }
+}
+
+/// One `merge_with` call on a `ServerVectorClock`
+#[derive(Debug, Clone)]
+pub struct MergeRecord {
+    pub before: StandardVectorClock,
+    pub incoming: StandardVectorClock,
+    pub after: StandardVectorClock,
+}
+
+struct MergeAudit {
+    capacity: usize,
+    records: VecDeque<MergeRecord>,
 }
 
 pub struct ServerVectorClock {
     server: u64,
     clock: RwLock<VectorClock<u64>>,
+    audit: Option<Mutex<MergeAudit>>,
+}
+
+/// An empty clock counting for server id 0
+impl Default for ServerVectorClock {
+    fn default() -> ServerVectorClock {
+        ServerVectorClock::from_id(0)
+    }
 }
 
 impl ServerVectorClock {
     pub fn new(server_address: &String) -> ServerVectorClock {
+        Self::from_id(hash_str(server_address))
+    }
+    /// Use `id` as the server id as is, `new` hashes the address instead
+    pub fn from_id(id: u64) -> ServerVectorClock {
         ServerVectorClock {
-            server: hash_str(server_address),
+            server: id,
             clock: RwLock::new(VectorClock::new()),
+            audit: None,
         }
     }
+    pub fn server_id(&self) -> u64 {
+        self.server
+    }
+    /// Also keep the last `capacity` merges for debugging divergence, see `merge_audit`
+    pub fn with_merge_audit(server_address: &String, capacity: usize) -> ServerVectorClock {
+        let mut clock = Self::new(server_address);
+        clock.audit = Some(Mutex::new(MergeAudit {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }));
+        clock
+    }
     pub fn inc(&self) -> StandardVectorClock {
         let mut clock = self.clock.write();
         clock.inc(self.server)
     }
+    /// `inc` for callers that do not need the resulting clock
+    pub fn inc_in_place(&self) {
+        self.clock.write().inc_in_place(self.server)
+    }
+    /// `inc` for `n` local events at once, under one lock. `n` must not be zero,
+    /// the counter saturates like with `inc`
+    pub fn inc_by(&self, n: u64) -> StandardVectorClock {
+        debug_assert!(n > 0);
+        let mut clock = self.clock.write();
+        {
+            let counter = clock.map.entry(self.server).or_insert(0);
+            *counter = counter.saturating_add(n);
+        }
+        clock.clone()
+    }
 
     pub fn happened_before(&self, clock_b: &StandardVectorClock) -> bool {
         let clock = self.clock.read();
@@ -157,7 +1064,40 @@ impl ServerVectorClock {
     }
     pub fn merge_with(&self, clock_b: &StandardVectorClock) {
         let mut clock = self.clock.write();
-        clock.merge_with(clock_b)
+        match self.audit {
+            Some(ref audit) => {
+                let before = clock.clone();
+                clock.merge_with(clock_b);
+                let mut audit = audit.lock();
+                if audit.capacity == 0 {
+                    return;
+                }
+                if audit.records.len() == audit.capacity {
+                    audit.records.pop_front();
+                }
+                audit.records.push_back(MergeRecord {
+                    before,
+                    incoming: clock_b.clone(),
+                    after: clock.clone(),
+                });
+            }
+            None => clock.merge_with(clock_b),
+        }
+    }
+    /// Merge all `clocks` at once, recorded as a single merge when audited
+    pub fn join_into(&self, clocks: &[StandardVectorClock]) {
+        self.merge_with(&VectorClock::join(clocks))
+    }
+    /// Recent merges, oldest first. Empty unless created with `with_merge_audit`
+    pub fn merge_audit(&self) -> Vec<MergeRecord> {
+        match self.audit {
+            Some(ref audit) => audit.lock().records.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+    pub fn prune(&self, live: &BTreeSet<u64>) -> usize {
+        let mut clock = self.clock.write();
+        clock.prune(live)
     }
     pub fn learn_from(&self, clock_b: &StandardVectorClock) {
         let mut clock = self.clock.write();
@@ -167,6 +1107,73 @@ impl ServerVectorClock {
         let clock = self.clock.read();
         clock.clone()
     }
+    /// Forget everything counted so far, e.g. after installing a full snapshot
+    pub fn reset(&self) {
+        self.clock.write().clear();
+    }
+    /// Replace the clock with a persisted one, as is.
+    /// Call it before the server processes any message, whatever was counted so far is lost
+    pub fn restore(&self, clock: StandardVectorClock) {
+        *self.clock.write() = clock;
+    }
+    /// Current clock and how far each server advanced since `previous`, taken under one read lock
+    pub fn snapshot_delta(
+        &self,
+        previous: &StandardVectorClock,
+    ) -> (StandardVectorClock, Vec<(u64, u64)>) {
+        let clock = self.clock.read().clone();
+        let deltas = clock
+            .map
+            .iter()
+            .filter_map(|(server, counter)| {
+                let prev = *previous.map.get(server).unwrap_or(&0);
+                if *counter > prev {
+                    Some((*server, counter - prev))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        (clock, deltas)
+    }
 }
 
-pub type StandardVectorClock = VectorClock<u64>;
+pub type StandardVectorClock = VectorClock<u64, u64>;
+
+/// Serde form of a clock as a sequence of `(server, counter)` pairs sorted by server,
+/// for fields marked `#[serde(with = "bifrost::vector_clock::compact")]`.
+/// Shorter than the default map form in self describing formats like JSON,
+/// bincode writes the same bytes for both.
+pub mod compact {
+    use super::{Counter, VectorClock};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S, C, Ser>(
+        clock: &VectorClock<S, C>,
+        serializer: Ser,
+    ) -> Result<Ser::Ok, Ser::Error>
+    where
+        S: Ord + Eq + Copy + Serialize,
+        C: Counter + Serialize,
+        Ser: Serializer,
+    {
+        let pairs: Vec<(S, C)> = clock
+            .map
+            .iter()
+            .map(|(server, counter)| (*server, *counter))
+            .collect();
+        pairs.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, S, C, D>(deserializer: D) -> Result<VectorClock<S, C>, D::Error>
+    where
+        S: Ord + Eq + Copy + Deserialize<'de>,
+        C: Counter + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let pairs: Vec<(S, C)> = Vec::deserialize(deserializer)?;
+        Ok(VectorClock {
+            map: pairs.into_iter().collect(),
+        })
+    }
+}
@@ -1,4 +1,4 @@
-use bifrost::raft::client::RaftClient;
+use bifrost::raft::client::{RaftClient, RaftClientConfig};
 use bifrost::raft::state_machine::callback::client::SubscriptionService;
 use bifrost::raft::state_machine::callback::server::SMCallback;
 use bifrost::raft::state_machine::master::ExecError;
@@ -6,12 +6,16 @@ use bifrost::raft::state_machine::StateMachineCtl;
 use bifrost::raft::*;
 use bifrost::rpc::Server;
 
+use super::mock::ReversingCodec;
 use super::wait;
 
 use futures::prelude::*;
+use parking_lot::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 pub struct Trigger {
     count: u64,
@@ -97,3 +101,48 @@ fn dummy() {
     assert_eq!(counter.load(Ordering::Relaxed), loops);
     assert_eq!(sumer.load(Ordering::Relaxed), expected_sum);
 }
+
+#[test]
+fn custom_codec() {
+    let addr = String::from("127.0.0.1:2111");
+    let codec = Arc::new(ReversingCodec::new());
+    let raft_service = RaftService::new_with_codec(
+        Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: DEFAULT_SERVICE_ID,
+        },
+        codec.clone(),
+    );
+    let server = Server::new(&addr);
+    let dummy_sm = Trigger {
+        count: 0,
+        callback: SMCallback::new(10, raft_service.clone()),
+    };
+    let sm_id = dummy_sm.id();
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    RaftService::start(&raft_service);
+    raft_service.register_state_machine(Box::new(dummy_sm));
+    raft_service.bootstrap();
+
+    wait();
+
+    let mut config = RaftClientConfig::default();
+    config.codec = codec.clone();
+    let raft_client = RaftClient::with_config(&vec![addr], DEFAULT_SERVICE_ID, config).unwrap();
+    let sm_client = client::SMClient::new(sm_id, &raft_client);
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    RaftClient::prepare_subscription(&server);
+    sm_client
+        .on_trigged(move |res| tx.lock().send(res).unwrap())
+        .wait()
+        .unwrap()
+        .unwrap();
+    sm_client.trigger().wait().unwrap().unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Ok(1)));
+    // both the server and the client went through the codec, including the subscription message
+    assert!(codec.encoded.load(Ordering::Relaxed) > 0);
+    assert!(codec.decoded.load(Ordering::Relaxed) > 0);
+}
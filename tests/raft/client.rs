@@ -0,0 +1,1301 @@
+use super::mock::{self, commands, MockRaft, ReversingCodec};
+use bifrost::raft::client::{
+    BincodeCodec, CallOutcome, CallSpan, Metrics, RaftClient, RaftClientConfig, ReadConsistency,
+    Tracer,
+};
+use bifrost::raft::state_machine::callback::{self, SubKey};
+use bifrost::raft::state_machine::configs::commands::on_leader_elected;
+use bifrost::raft::state_machine::configs::CONFIG_SM_ID;
+use bifrost::raft::state_machine::master::{ExecError, RetryReason};
+use bifrost::raft::state_machine::OpType;
+use bifrost::raft::*;
+use bifrost::rpc;
+use bifrost::utils::bincode::{deserialize, serialize};
+use bifrost_hasher::hash_str;
+use futures::prelude::*;
+use log::{self, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+use parking_lot::Mutex;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SM_ID: u64 = 10;
+
+#[test]
+fn hedging_slow_leader() {
+    let mocks = mock::cluster(&[2300, 2301, 2302]);
+    mocks[0].on_command(|mock: &MockRaft, entry| {
+        if mock.commands.load(Ordering::Relaxed) == 1 {
+            thread::sleep(Duration::from_secs(2));
+        }
+        mock.default_command(entry)
+    });
+    let mut config = RaftClientConfig::default();
+    config.hedge_after = Some(Duration::from_millis(200));
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let start = Instant::now();
+    let res = client.execute(SM_ID, commands::put::new(&1)).wait();
+    assert_eq!(res.unwrap(), Ok(1));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    // let the slow copy land, it carries the token of the applied one and is skipped
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 2);
+    assert_eq!(mocks[0].last_log_id.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn watch_commit_progress() {
+    let mocks = mock::cluster(&[2303]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let observed = Arc::new(Mutex::new(Vec::new()));
+    let observed_clone = observed.clone();
+    client.watch_commit_progress(move |id| observed_clone.lock().push(id));
+    for i in 0..5 {
+        client.execute(SM_ID, commands::put::new(&i)).wait().unwrap().unwrap();
+    }
+    let observed = observed.lock();
+    assert_eq!(observed.len(), 5);
+    for pair in observed.windows(2) {
+        assert!(pair[0] < pair[1]);
+    }
+}
+
+#[test]
+fn connection_stats() {
+    let mocks = mock::cluster(&[2304, 2305, 2306]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    for i in 0..4 {
+        client.execute(SM_ID, commands::put::new(&i)).wait().unwrap().unwrap();
+    }
+    for _ in 0..6 {
+        client.execute(SM_ID, commands::get::new()).wait().unwrap().unwrap();
+    }
+    let stats = client.connection_stats();
+    assert_eq!(stats.len(), 3);
+    let total: u64 = stats.iter().map(|stat| stat.requests_sent).sum();
+    assert_eq!(total, 10);
+    for stat in &stats {
+        let mock = mocks.iter().find(|mock| mock.id == stat.member_id).unwrap();
+        let served = mock.commands.load(Ordering::Relaxed) + mock.queries.load(Ordering::Relaxed);
+        assert_eq!(stat.requests_sent, served as u64);
+        assert_eq!(stat.address, mock.address);
+    }
+}
+
+#[test]
+fn leader_priority() {
+    let mocks = mock::cluster(&[2307, 2308, 2309]);
+    let mut config = RaftClientConfig::default();
+    config.leader_priority = vec![mocks[2].address.clone(), mocks[1].address.clone()];
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    assert_eq!(client.leader_id(), mocks[0].id);
+    // leader fails and the member with the highest priority takes over
+    mocks[0].down.store(true, Ordering::Relaxed);
+    for mock in &mocks {
+        mock.leader_id.store(mocks[2].id, Ordering::Relaxed);
+    }
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    assert_eq!(client.leader_id(), mocks[2].id);
+    assert_eq!(mocks[1].commands.load(Ordering::Relaxed), 0);
+    assert_eq!(mocks[2].commands.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn single_discovery_on_leader_change() {
+    let mocks = mock::cluster(&[2310, 2311, 2312]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let infos = |mocks: &Vec<Arc<MockRaft>>| -> usize {
+        mocks.iter().map(|m| m.infos.load(Ordering::Relaxed)).sum()
+    };
+    let infos_before = infos(&mocks);
+    for mock in &mocks {
+        mock.leader_id.store(mocks[1].id, Ordering::Relaxed);
+    }
+    let threads: Vec<_> = (0..20)
+        .map(|i| {
+            let client = client.clone();
+            thread::spawn(move || {
+                client.execute(SM_ID, commands::put::new(&i)).wait().unwrap().unwrap();
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(client.leader_id(), mocks[1].id);
+    assert_eq!(infos(&mocks), infos_before + 1);
+}
+
+#[test]
+fn read_index_barrier() {
+    let mocks = mock::cluster(&[2313, 2314]);
+    mocks[0].last_log_id.store(5, Ordering::Relaxed);
+    mocks[1].last_log_id.store(3, Ordering::Relaxed);
+    // follower applies one log every time it is asked
+    mocks[1].on_query(|mock: &MockRaft, entry| {
+        if entry.id > mock.last_log_id.load(Ordering::Relaxed) {
+            mock.last_log_id.fetch_add(1, Ordering::Relaxed);
+            return ClientQryResponse::LeftBehind;
+        }
+        mock.default_query(entry)
+    });
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let res = client
+        .query_read_index(SM_ID, commands::get::new())
+        .wait()
+        .unwrap();
+    assert_eq!(res, Ok(5));
+    assert_eq!(mocks[0].queries.load(Ordering::Relaxed), 0);
+    assert_eq!(mocks[1].queries.load(Ordering::Relaxed), 3);
+
+    // the uncommitted tail of the leader is not waited for
+    mocks[0].last_log_id.store(8, Ordering::Relaxed);
+    mocks[0].uncommitted.store(2, Ordering::Relaxed);
+    let res = client
+        .query_read_index(SM_ID, commands::get::new())
+        .wait()
+        .unwrap();
+    assert_eq!(res, Ok(6));
+    assert_eq!(mocks[1].queries.load(Ordering::Relaxed), 5);
+
+    // a leader that knows it was deposed gives no read index
+    mocks[0].leader_id.store(mocks[1].id, Ordering::Relaxed);
+    match client.query_read_index(SM_ID, commands::get::new()).wait() {
+        Err(ExecError::NotLeader(leader_id)) => assert_eq!(leader_id, mocks[1].id),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(mocks[1].queries.load(Ordering::Relaxed), 5);
+}
+
+#[test]
+fn custom_codec() {
+    let mocks = mock::cluster(&[2315]);
+    mocks[0].on_command(|_: &MockRaft, mut entry| {
+        entry.data.reverse();
+        let (v,): (u64,) = deserialize(&entry.data);
+        let mut data = serialize(&Ok::<u64, ()>(v * 2));
+        data.reverse();
+        ClientCmdResponse::Success {
+            data: Ok(data),
+            last_log_id: 1,
+            last_log_term: 1,
+        }
+    });
+    let codec = Arc::new(ReversingCodec::new());
+    let mut config = RaftClientConfig::default();
+    config.codec = codec.clone();
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let res = client.execute(SM_ID, commands::put::new(&21)).wait().unwrap();
+    assert_eq!(res, Ok(42));
+    assert_eq!(codec.encoded.load(Ordering::Relaxed), 1);
+    assert_eq!(codec.decoded.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn build_log_entry() {
+    let mocks = mock::cluster(&[2316]);
+    mocks[0].term.store(4, Ordering::Relaxed);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    for i in 0..3 {
+        client.execute(SM_ID, commands::put::new(&i)).wait().unwrap().unwrap();
+    }
+    let entry = client.build_log_entry(SM_ID, 7, &[1, 2, 3]);
+    assert_eq!(entry.id, 3);
+    assert_eq!(entry.term, 4);
+    assert_eq!(entry.sm_id, SM_ID);
+    assert_eq!(entry.fn_id, 7);
+    assert_eq!(entry.data, vec![1, 2, 3]);
+}
+
+#[test]
+fn throttled_command() {
+    let mocks = mock::cluster(&[2317]);
+    mocks[0].on_command(|mock: &MockRaft, entry| {
+        if mock.commands.load(Ordering::Relaxed) == 1 {
+            return ClientCmdResponse::Throttled {
+                retry_after_ms: 500,
+            };
+        }
+        mock.default_command(entry)
+    });
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let start = Instant::now();
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 2);
+
+    mocks[0].on_command(|_: &MockRaft, _| ClientCmdResponse::Throttled { retry_after_ms: 10 });
+    match client.execute(SM_ID, commands::put::new(&1)).wait() {
+        Err(ExecError::Throttled) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn exhausted_retry_reasons() {
+    let mocks = mock::cluster(&[2318]);
+    mocks[0].on_command(|mock: &MockRaft, _| match mock.commands.load(Ordering::Relaxed) {
+        1 => ClientCmdResponse::NotLeader(mock.id),
+        2 => ClientCmdResponse::Throttled { retry_after_ms: 0 },
+        _ => {
+            // every following request fails on the server
+            mock.down.store(true, Ordering::Relaxed);
+            ClientCmdResponse::NotCommitted
+        }
+    });
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    match client.execute(SM_ID, commands::put::new(&1)).wait() {
+        Err(ExecError::ExhaustedRetries { attempts }) => assert_eq!(
+            attempts,
+            vec![
+                RetryReason::NotLeader,
+                RetryReason::Throttled,
+                RetryReason::NotCommitted,
+                RetryReason::ServerError,
+                RetryReason::ServerError,
+            ]
+        ),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn quorum_confirmed_read() {
+    let mocks = mock::cluster(&[2319, 2320, 2321, 2322]);
+    for mock in &mocks {
+        mock.last_log_id.store(7, Ordering::Relaxed);
+    }
+    // one follower answers with a diverged state
+    mocks[2].on_query(|_: &MockRaft, _| ClientQryResponse::Success {
+        data: mock::reply(99),
+        last_log_id: 7,
+        last_log_term: 1,
+    });
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    for _ in 0..3 {
+        let res = client
+            .query_quorum_confirmed(SM_ID, commands::get::new(), 3)
+            .wait()
+            .unwrap();
+        assert_eq!(res, Ok(7));
+    }
+    assert_eq!(mocks[0].queries.load(Ordering::Relaxed), 0);
+    assert_eq!(mocks[2].queries.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn blacklist_recovery() {
+    let mocks = mock::cluster(&[2323, 2324, 2325]);
+    let mut config = RaftClientConfig::default();
+    config.blacklist_after = 2;
+    config.probe_interval = Duration::from_millis(200);
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    mocks[1].down.store(true, Ordering::Relaxed);
+    // queries go round robin, every member is asked twice
+    for _ in 0..6 {
+        let _ = client.execute(SM_ID, commands::get::new()).wait();
+    }
+    let blacklisted = client.blacklisted_members();
+    assert_eq!(blacklisted.len(), 1);
+    assert_eq!(blacklisted[0].0, mocks[1].id);
+    // queries go around the blacklisted member
+    let asked = mocks[1].queries.load(Ordering::Relaxed);
+    for _ in 0..4 {
+        assert!(client.execute(SM_ID, commands::get::new()).wait().is_ok());
+    }
+    assert_eq!(mocks[1].queries.load(Ordering::Relaxed), asked);
+    // still down, probes keep failing
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.blacklisted_members().len(), 1);
+    mocks[1].down.store(false, Ordering::Relaxed);
+    thread::sleep(Duration::from_millis(500));
+    assert!(client.blacklisted_members().is_empty());
+}
+
+struct RecordingTracer {
+    spans: Mutex<Vec<CallSpan>>,
+}
+
+impl Tracer for RecordingTracer {
+    fn start_span(&self, sm_id: u64, fn_id: u64, _op: OpType) -> Option<Vec<u8>> {
+        Some(serialize(&(sm_id, fn_id)))
+    }
+    fn end_span(&self, span: CallSpan) {
+        self.spans.lock().push(span);
+    }
+}
+
+#[test]
+fn tracing_spans() {
+    let mocks = mock::cluster(&[2326]);
+    let contexts = Arc::new(Mutex::new(Vec::new()));
+    let seen = contexts.clone();
+    mocks[0].on_command(move |mock: &MockRaft, entry: LogEntry| {
+        seen.lock().push(entry.trace_context.clone());
+        if mock.commands.load(Ordering::Relaxed) == 1 {
+            return ClientCmdResponse::NotCommitted;
+        }
+        mock.default_command(entry)
+    });
+    let tracer = Arc::new(RecordingTracer {
+        spans: Mutex::new(Vec::new()),
+    });
+    let mut config = RaftClientConfig::default();
+    config.tracer = tracer.clone();
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let (put_fn, _, _) = commands::put::new(&1).encode(&BincodeCodec);
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    client.execute(SM_ID, commands::get::new()).wait().unwrap().unwrap();
+
+    let spans = tracer.spans.lock();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0].sm_id, SM_ID);
+    assert_eq!(spans[0].fn_id, put_fn);
+    assert_eq!(spans[0].op, OpType::COMMAND);
+    assert_eq!(spans[0].leader_id, mocks[0].id);
+    assert_eq!(spans[0].retries, 1);
+    assert!(spans[0].succeeded);
+    assert_eq!(spans[1].op, OpType::QUERY);
+    assert_eq!(spans[1].retries, 0);
+    // every attempt carries the context of its call
+    let expected = Some(serialize(&(SM_ID, put_fn)));
+    assert_eq!(*contexts.lock(), vec![expected.clone(), expected]);
+}
+
+#[test]
+fn connect_timeout() {
+    let mocks = mock::cluster(&[2328]);
+    // a member behind an address that never completes the handshake
+    let unreachable = String::from("10.255.255.1:2329");
+    mocks[0].members.write().push((hash_str(&unreachable), unreachable));
+    let mut config = RaftClientConfig::default();
+    config.connect_timeout = Some(Duration::from_millis(300));
+    let start = Instant::now();
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert_eq!(client.connection_stats().len(), 1);
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+}
+
+#[test]
+fn keep_all_connected() {
+    let mocks = mock::cluster(&[2330, 2331]);
+    // the third member is not up when the client discovers the cluster
+    let late = String::from("127.0.0.1:2332");
+    for mock in &mocks {
+        mock.members.write().push((hash_str(&late), late.clone()));
+    }
+    let mut config = RaftClientConfig::default();
+    config.keep_all_connected = true;
+    config.probe_interval = Duration::from_millis(200);
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    assert_eq!(client.connection_stats().len(), 2);
+    let members = mocks[0].members.read().clone();
+    let _late_mock = mock::start(&late, &members, mocks[0].id);
+    thread::sleep(Duration::from_millis(1500));
+    let mut connected: Vec<u64> = client
+        .connection_stats()
+        .iter()
+        .map(|stat| stat.member_id)
+        .collect();
+    connected.sort();
+    let mut expected: Vec<u64> = members.iter().map(|&(id, _)| id).collect();
+    expected.sort();
+    assert_eq!(connected, expected);
+
+    // the connection to a follower drops, the keeper replaces it once it answers again
+    let dropped = rpc::DEFAULT_CLIENT_POOL.get(&mocks[1].address).unwrap();
+    mocks[1].down.store(true, Ordering::Relaxed);
+    thread::sleep(Duration::from_millis(600));
+    mocks[1].down.store(false, Ordering::Relaxed);
+    thread::sleep(Duration::from_millis(600));
+    let restored = rpc::DEFAULT_CLIENT_POOL.get(&mocks[1].address).unwrap();
+    assert!(!Arc::ptr_eq(&dropped, &restored));
+    assert!(client
+        .connection_stats()
+        .iter()
+        .any(|stat| stat.member_id == mocks[1].id));
+}
+
+#[test]
+fn default_read_consistency() {
+    let mocks = mock::cluster(&[2333, 2334, 2335]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    // the leader is ahead of both followers after this command
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    let queries = || -> Vec<usize> {
+        mocks
+            .iter()
+            .map(|mock| mock.queries.load(Ordering::Relaxed))
+            .collect()
+    };
+
+    client.set_default_read_consistency(ReadConsistency::Linearizable);
+    for _ in 0..3 {
+        assert_eq!(client.execute(SM_ID, commands::get::new()).wait().unwrap(), Ok(1));
+    }
+    assert_eq!(queries(), vec![3, 0, 0]);
+
+    client.set_default_read_consistency(ReadConsistency::Stale);
+    for _ in 0..3 {
+        client.execute(SM_ID, commands::get::new()).wait().unwrap().unwrap();
+    }
+    // round robin, lagging followers answer without being left behind
+    assert_eq!(queries(), vec![4, 1, 1]);
+
+    let res = client.execute_with_consistency(
+        SM_ID,
+        commands::get::new(),
+        ReadConsistency::Linearizable,
+    );
+    assert_eq!(res.wait().unwrap(), Ok(1));
+    assert_eq!(queries(), vec![5, 1, 1]);
+}
+
+#[test]
+fn fenced_command() {
+    let mocks = mock::cluster(&[2336]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let res = client.command_fenced(SM_ID, commands::put::new(&1), 1).wait();
+    assert_eq!(res.unwrap(), Ok(1));
+    // a new election happened since the caller observed term 1
+    mocks[0].term.store(2, Ordering::Relaxed);
+    match client.command_fenced(SM_ID, commands::put::new(&2), 1).wait() {
+        Err(ExecError::TermChanged) => {}
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(mocks[0].last_log_id.load(Ordering::Relaxed), 1);
+    let res = client.command_fenced(SM_ID, commands::put::new(&3), 2).wait();
+    assert_eq!(res.unwrap(), Ok(2));
+}
+
+struct RecordingMetrics {
+    latencies: Mutex<Vec<(OpType, CallOutcome, Duration)>>,
+}
+
+impl Metrics for RecordingMetrics {
+    fn record_latency(&self, op: OpType, outcome: CallOutcome, duration: Duration) {
+        self.latencies.lock().push((op, outcome, duration));
+    }
+}
+
+#[test]
+fn latency_metrics() {
+    let mocks = mock::cluster(&[2337]);
+    mocks[0].on_command(|mock: &MockRaft, entry| match mock.commands.load(Ordering::Relaxed) {
+        2 => ClientCmdResponse::Throttled { retry_after_ms: 200 },
+        3 | 4 => ClientCmdResponse::Throttled { retry_after_ms: 0 },
+        n if n >= 6 && n <= 10 => ClientCmdResponse::NotCommitted,
+        _ => mock.default_command(entry),
+    });
+    let metrics = Arc::new(RecordingMetrics {
+        latencies: Mutex::new(Vec::new()),
+    });
+    let mut config = RaftClientConfig::default();
+    config.metrics = metrics.clone();
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    // first attempt, then throttled three times, then never committed
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    assert!(client.execute(SM_ID, commands::put::new(&1)).wait().is_err());
+    client.execute(SM_ID, commands::get::new()).wait().unwrap().unwrap();
+
+    let latencies = metrics.latencies.lock();
+    let labels: Vec<(OpType, CallOutcome)> = latencies
+        .iter()
+        .map(|&(op, outcome, _)| (op, outcome))
+        .collect();
+    assert_eq!(
+        labels,
+        vec![
+            (OpType::COMMAND, CallOutcome::Success),
+            (OpType::COMMAND, CallOutcome::Retried),
+            (OpType::COMMAND, CallOutcome::Failed),
+            (OpType::QUERY, CallOutcome::Success),
+        ]
+    );
+    // the retried call includes the time spent waiting out the throttle
+    assert!(latencies[1].2 >= Duration::from_millis(200));
+}
+
+#[test]
+fn drop_removed_member_connection() {
+    let mocks = mock::cluster(&[2338, 2339, 2340]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let removed = mocks[2].id;
+    assert!(rpc::DEFAULT_CLIENT_POOL.contains(removed));
+    for mock in &mocks {
+        mock.members.write().retain(|&(id, _)| id != removed);
+    }
+    // the leader change makes the client refresh its members
+    mocks[0].on_command(|mock: &MockRaft, entry| {
+        if mock.commands.load(Ordering::Relaxed) == 1 {
+            return ClientCmdResponse::NotLeader(mock.id);
+        }
+        mock.default_command(entry)
+    });
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    assert!(client.connection_stats().iter().all(|stat| stat.member_id != removed));
+    assert!(!rpc::DEFAULT_CLIENT_POOL.contains(removed));
+    // queries only go to the remaining members
+    for _ in 0..4 {
+        client.execute(SM_ID, commands::get::new()).wait().unwrap().unwrap();
+    }
+    assert_eq!(mocks[2].queries.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn recent_commands() {
+    let mocks = mock::cluster(&[2341]);
+    let mut config = RaftClientConfig::default();
+    config.command_log_size = 3;
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    for i in &[0, 1, 2, 2] {
+        client.execute(SM_ID, commands::put::new(i)).wait().unwrap().unwrap();
+    }
+    // queries are not recorded
+    client.execute(SM_ID, commands::get::new()).wait().unwrap().unwrap();
+    mocks[0].on_command(|_: &MockRaft, _| ClientCmdResponse::NotCommitted);
+    assert!(client.execute(SM_ID, commands::put::new(&4)).wait().is_err());
+
+    let records = client.recent_commands();
+    assert_eq!(records.len(), 3);
+    let (put_fn, _, _) = commands::put::new(&0).encode(&BincodeCodec);
+    assert!(records.iter().all(|r| r.sm_id == SM_ID && r.fn_id == put_fn));
+    // the tokens sent to the server, identical commands included
+    assert_ne!(records[0].token, records[1].token);
+    {
+        let applied_tokens = mocks[0].applied_tokens.read();
+        assert!(applied_tokens.contains_key(&records[0].token));
+        assert!(applied_tokens.contains_key(&records[1].token));
+        assert!(!applied_tokens.contains_key(&records[2].token));
+    }
+    assert_eq!(records[0].outcome, CallOutcome::Success);
+    assert_eq!(records[2].outcome, CallOutcome::Failed);
+    assert!(records[0].timestamp <= records[2].timestamp);
+}
+
+#[test]
+fn leader_tracking() {
+    let mocks = mock::cluster(&[2342]);
+    let subscription: Arc<Mutex<Option<(SubKey, String)>>> = Arc::new(Mutex::new(None));
+    let subscription_clone = subscription.clone();
+    mocks[0].on_command(move |mock: &MockRaft, entry| {
+        if entry.sm_id == CONFIG_SM_ID {
+            let (key, address, _): (SubKey, String, u64) = deserialize(&entry.data);
+            *subscription_clone.lock() = Some((key, address));
+        }
+        mock.default_command(entry)
+    });
+    let server = rpc::Server::new(&String::from("127.0.0.1:2343"));
+    rpc::Server::listen_and_resume(&server);
+    RaftClient::prepare_subscription(&server);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    client.enable_leader_tracking().wait().unwrap().unwrap();
+    assert_eq!(client.leader_id(), mocks[0].id);
+    let commands_sent = mocks[0].commands.load(Ordering::Relaxed);
+
+    // the mock plays a newly elected leader announcing itself
+    let (key, address) = subscription.lock().clone().unwrap();
+    let new_leader = hash_str(&String::from("127.0.0.1:2344"));
+    let callback_client = rpc::DEFAULT_CLIENT_POOL.get(&address).unwrap();
+    let (_, _, pattern) = on_leader_elected::new().encode(&BincodeCodec);
+    callback::AsyncServiceClient::new(callback::DEFAULT_SERVICE_ID, &callback_client)
+        .notify(key, pattern.clone(), serialize(&Ok::<_, ()>((new_leader, 2u64))))
+        .wait()
+        .unwrap()
+        .unwrap();
+    assert_eq!(client.leader_id(), new_leader);
+    assert_eq!(client.leader_term(), 2);
+
+    // a late event from an older term is ignored
+    callback::AsyncServiceClient::new(callback::DEFAULT_SERVICE_ID, &callback_client)
+        .notify(key, pattern, serialize(&Ok::<_, ()>((mocks[0].id, 1u64))))
+        .wait()
+        .unwrap()
+        .unwrap();
+    assert_eq!(client.leader_id(), new_leader);
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), commands_sent);
+}
+
+#[test]
+fn optimistic_query() {
+    let mocks = mock::cluster(&[2344]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    fn on_update(tx: Sender<u64>) -> impl Fn(Result<Result<u64, ()>, ExecError>) + Send {
+        move |res| tx.send(res.unwrap().unwrap()).unwrap()
+    }
+    let get = |tx: &Sender<u64>| {
+        client
+            .query_optimistic(SM_ID, commands::get::new(), on_update(tx.clone()))
+            .unwrap()
+    };
+    let (tx, rx) = channel();
+    // nothing cached yet, the verified value is delivered through the callback
+    assert_eq!(get(&tx), None);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 0);
+
+    // another client changed the state machine, the cached value is answered and then corrected
+    let other = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    other.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    assert_eq!(get(&tx), Some(Ok(0)));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+
+    // cache is up to date now, the callback stays quiet
+    assert_eq!(get(&tx), Some(Ok(1)));
+    assert!(rx.recv_timeout(Duration::from_secs(1)).is_err());
+
+    // a command from this client drops what it cached for the state machine
+    client.execute(SM_ID, commands::put::new(&2)).wait().unwrap().unwrap();
+    assert_eq!(get(&tx), None);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 2);
+
+    // commands are never answered from the cache
+    match client.query_optimistic(SM_ID, commands::put::new(&3), |_| {}) {
+        Err(ExecError::NotQuery) => {}
+        other => panic!("expected NotQuery, got {:?}", other),
+    }
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn pinned_leader() {
+    let mocks = mock::cluster(&[2345, 2346]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let leader = mocks[0].id;
+    client.pin_leader(mocks[1].id);
+    match client.execute(SM_ID, commands::put::new(&1)).wait() {
+        Err(ExecError::NotLeader(hint)) => assert_eq!(hint, leader),
+        other => panic!("expected NotLeader, got {:?}", other),
+    }
+    assert_eq!(mocks[1].commands.load(Ordering::Relaxed), 1);
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 0);
+    assert_eq!(client.leader_id(), leader);
+
+    client.unpin_leader();
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 1);
+    assert_eq!(mocks[1].commands.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn coalesced_queries() {
+    let mocks = mock::cluster(&[2347]);
+    mocks[0].on_query(|mock: &MockRaft, entry| {
+        thread::sleep(Duration::from_millis(500));
+        mock.default_query(entry)
+    });
+    let mut config = RaftClientConfig::default();
+    config.coalesce_queries = true;
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    let barrier = Arc::new(Barrier::new(8));
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let client = client.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                client.execute(SM_ID, commands::get::new()).wait().unwrap()
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), Ok(1));
+    }
+    assert_eq!(mocks[0].queries.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn coalesced_query_abandoned() {
+    let mocks = mock::cluster(&[2386]);
+    mocks[0].on_query(|mock: &MockRaft, entry| {
+        if mock.queries.load(Ordering::Relaxed) == 1 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        mock.default_query(entry)
+    });
+    let mut config = RaftClientConfig::default();
+    config.coalesce_queries = true;
+    config.rpc_timeout = Some(Duration::from_secs(5));
+    config.operation_timeout = Some(Duration::from_millis(300));
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    match client.execute(SM_ID, commands::get::new()).wait() {
+        Err(ExecError::Timeout) => {}
+        other => panic!("{:?}", other),
+    }
+    // the timed out query is dropped and no longer holds back identical ones
+    assert_eq!(client.execute(SM_ID, commands::get::new()).wait().unwrap(), Ok(0));
+}
+
+#[test]
+fn payload_too_large() {
+    let mocks = mock::cluster(&[2348]);
+    let mut config = RaftClientConfig::default();
+    config.max_payload_size = Some(64);
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let (put_fn, _, _) = commands::put::new(&0).encode(&BincodeCodec);
+    match client
+        .execute_raw(SM_ID, put_fn, OpType::COMMAND, vec![0; 65])
+        .wait()
+    {
+        Err(ExecError::PayloadTooLarge { size, limit }) => assert_eq!((size, limit), (65, 64)),
+        other => panic!("expected PayloadTooLarge, got {:?}", other),
+    }
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 0);
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 1);
+
+    // every kind of call checks the size, the encoded argument of put takes 8 bytes
+    let mut config = RaftClientConfig::default();
+    config.max_payload_size = Some(4);
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let results = vec![
+        client.query_read_index(SM_ID, commands::put::new(&1)).wait(),
+        client.query_quorum_confirmed(SM_ID, commands::put::new(&1), 1).wait(),
+        client.query_detailed(SM_ID, commands::put::new(&1)).wait().map(|(res, _)| res),
+        client.session("size").execute(SM_ID, commands::put::new(&1)).wait(),
+    ];
+    for res in results {
+        match res {
+            Err(ExecError::PayloadTooLarge { size, limit }) => assert_eq!((size, limit), (8, 4)),
+            other => panic!("expected PayloadTooLarge, got {:?}", other),
+        }
+    }
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 1);
+    assert_eq!(mocks[0].queries.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn member_clock_skew() {
+    let mocks = mock::cluster(&[2349, 2350]);
+    mocks[0].clock_offset.store(5_000, Ordering::Relaxed);
+    mocks[1].clock_offset.store(-3_000, Ordering::Relaxed);
+    let client = RaftClient::new(&vec![mocks[0].address.clone()], DEFAULT_SERVICE_ID).unwrap();
+    // the first member hands leadership over and goes away, discovery has to ask the other one
+    let new_leader = mocks[1].id;
+    mocks[1].leader_id.store(new_leader, Ordering::Relaxed);
+    mocks[0].on_command(move |mock: &MockRaft, _| {
+        mock.down.store(true, Ordering::Relaxed);
+        ClientCmdResponse::NotLeader(new_leader)
+    });
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+
+    let skews = client.member_clock_skew();
+    assert_eq!(skews.len(), 2);
+    let mut expected = vec![(mocks[0].id, 5_000), (mocks[1].id, -3_000)];
+    expected.sort();
+    for (&(id, skew), &(expected_id, expected_skew)) in skews.iter().zip(expected.iter()) {
+        assert_eq!(id, expected_id);
+        assert!((skew - expected_skew).abs() < 200, "skew {} for {}", skew, id);
+    }
+}
+
+#[test]
+fn wait_committed() {
+    let mocks = mock::cluster(&[2351, 2352]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    match client.wait_committed(5, Duration::from_millis(300)) {
+        Err(ExecError::Timeout) => {}
+        other => panic!("expected Timeout, got {:?}", other),
+    }
+    let lagging = mocks.clone();
+    let catch_up = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        for mock in &lagging {
+            mock.last_log_id.store(5, Ordering::Relaxed);
+        }
+    });
+    let start = Instant::now();
+    client.wait_committed(5, Duration::from_secs(5)).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    catch_up.join().unwrap();
+    // later reads must see at least the awaited log
+    let seen = client.execute(SM_ID, commands::get::new()).wait().unwrap().unwrap();
+    assert_eq!(seen, 5);
+}
+
+#[test]
+fn leader_failure_detection() {
+    let mocks = mock::cluster(&[2353, 2354, 2355]);
+    let mut config = RaftClientConfig::default();
+    config.leader_probe_interval = Some(Duration::from_millis(100));
+    config.leader_probe_failures = 2;
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    assert_eq!(client.leader_id(), mocks[0].id);
+    mocks[0].down.store(true, Ordering::Relaxed);
+    for mock in &mocks[1..] {
+        mock.leader_id.store(mocks[1].id, Ordering::Relaxed);
+    }
+    let start = Instant::now();
+    while client.leader_id() != mocks[1].id && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(client.leader_id(), mocks[1].id);
+    for mock in &mocks {
+        assert_eq!(mock.commands.load(Ordering::Relaxed), 0);
+    }
+}
+
+#[test]
+fn replication_factor() {
+    let mocks = mock::cluster(&[2356, 2357, 2358]);
+    // one address is enough to learn about the whole cluster
+    let client = RaftClient::new(&vec![mocks[2].address.clone()], DEFAULT_SERVICE_ID).unwrap();
+    assert_eq!(client.replication_factor(), 3);
+}
+
+#[test]
+fn sticky_session() {
+    let mocks = mock::cluster(&[2359, 2360, 2361]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let session = client.session("user-1");
+    // another thread with the same key shares the member and the watermark
+    let other = client.session("user-1");
+    assert_eq!(session.member_id(), other.member_id());
+    let member = mocks
+        .iter()
+        .find(|mock| mock.id == session.member_id())
+        .unwrap()
+        .clone();
+    let written = session
+        .execute(SM_ID, commands::put::new(&1))
+        .wait()
+        .unwrap()
+        .unwrap();
+    assert!(other.watermark() >= written);
+    // only the leader has the write, a lagging member must not answer for the session
+    let first = thread::spawn(move || other.execute(SM_ID, commands::get::new()).wait())
+        .join()
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(first >= written);
+    for mock in &mocks {
+        mock.last_log_id.store(first, Ordering::Relaxed);
+    }
+    let queries = member.queries.load(Ordering::Relaxed);
+    let second = session
+        .execute(SM_ID, commands::get::new())
+        .wait()
+        .unwrap()
+        .unwrap();
+    assert!(second >= first);
+    assert_eq!(member.queries.load(Ordering::Relaxed), queries + 1);
+    assert_eq!(session.watermark(), second);
+}
+
+#[test]
+fn op_type_override() {
+    let mocks = mock::cluster(&[2362, 2363]);
+    // the leader never answers a query, a retry goes to the follower
+    mocks[0].on_query(|_: &MockRaft, _| ClientQryResponse::LeftBehind);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    let (put_fn, _, _) = commands::put::new(&1).encode(&BincodeCodec);
+    client.override_op_type(SM_ID, put_fn, OpType::QUERY);
+    let res = client.execute(SM_ID, commands::put::new(&1)).wait();
+    assert!(res.unwrap().is_ok());
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 0);
+    assert_eq!(mocks[1].queries.load(Ordering::Relaxed), 1);
+    // the same function id on another state machine keeps its declared type
+    let res = client.execute(SM_ID + 1, commands::put::new(&1)).wait();
+    assert!(res.unwrap().is_ok());
+    assert_eq!(mocks[0].commands.load(Ordering::Relaxed), 1);
+    assert_eq!(mocks[1].queries.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn last_cluster_info() {
+    let mocks = mock::cluster(&[2364, 2365]);
+    mocks[0].last_log_id.store(3, Ordering::Relaxed);
+    let client = RaftClient::new(&vec![mocks[0].address.clone()], DEFAULT_SERVICE_ID).unwrap();
+    let info = client.last_cluster_info().unwrap();
+    let expected = mocks[0].cluster_info();
+    assert_eq!(info.members, expected.members);
+    assert_eq!(info.leader_id, mocks[0].id);
+    assert_eq!(info.last_log_id, 3);
+    assert_eq!(info.last_log_term, expected.last_log_term);
+}
+
+#[test]
+fn paused_discovery() {
+    let mocks = mock::cluster(&[2366, 2367]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    // leadership moved to the second member, the old leader does not know where
+    for mock in &mocks {
+        mock.leader_id.store(mocks[1].id, Ordering::Relaxed);
+    }
+    mocks[0].on_command(|_: &MockRaft, _| ClientCmdResponse::NotLeader(0));
+    let infos = || {
+        mocks
+            .iter()
+            .map(|m| m.infos.load(Ordering::Relaxed))
+            .sum::<usize>()
+    };
+    let seen = infos();
+    client.pause_discovery();
+    match client.execute(SM_ID, commands::put::new(&1)).wait() {
+        Err(ExecError::DiscoveryPaused) => {}
+        other => panic!("expected DiscoveryPaused, got {:?}", other),
+    }
+    assert_eq!(infos(), seen);
+    client.resume_discovery();
+    let res = client.execute(SM_ID, commands::put::new(&1)).wait();
+    assert!(res.unwrap().is_ok());
+    assert!(infos() > seen);
+    assert_eq!(client.leader_id(), mocks[1].id);
+}
+
+#[test]
+fn subscription_pattern_collision() {
+    let server = rpc::Server::new(&String::from("127.0.0.1:2368"));
+    rpc::Server::listen_and_resume(&server);
+    let service = callback::client::SubscriptionService::initialize(&server);
+    // two patterns forced onto the same key, as if their hashes collided
+    let key: SubKey = (1, SM_ID, 2, 3);
+    let (tx, rx) = channel();
+    for (sub_id, pattern) in vec![(0, b"a".to_vec()), (1, b"b".to_vec())] {
+        let tx = Mutex::new(tx.clone());
+        let deliver = move |data: Vec<u8>| tx.lock().send((sub_id, data)).unwrap();
+        service
+            .subs
+            .write()
+            .entry(key)
+            .or_insert_with(Vec::new)
+            .push((Box::new(deliver), sub_id, pattern));
+    }
+    let rpc_client = rpc::DEFAULT_CLIENT_POOL.get(server.address()).unwrap();
+    let client = callback::AsyncServiceClient::new(callback::DEFAULT_SERVICE_ID, &rpc_client);
+    let events = vec![
+        (b"b".to_vec(), vec![7]),
+        (b"a".to_vec(), vec![8]),
+        (b"c".to_vec(), vec![9]),
+    ];
+    for (pattern, data) in events {
+        client.notify(key, pattern, data).wait().unwrap().unwrap();
+    }
+    let delivered: Vec<(u64, Vec<u8>)> = rx.try_iter().collect();
+    assert_eq!(delivered, vec![(1, vec![7]), (0, vec![8])]);
+}
+
+#[test]
+fn query_staleness() {
+    let mocks = mock::cluster(&[2369, 2370]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    for i in 0..3 {
+        client.execute(SM_ID, commands::put::new(&i)).wait().unwrap().unwrap();
+    }
+    // the follower is two entries behind the leader and the client
+    mocks[1].last_log_id.store(1, Ordering::Relaxed);
+    for _ in 0..2 {
+        let (seen, staleness) = client
+            .query_detailed(SM_ID, commands::get::new())
+            .wait()
+            .unwrap();
+        assert_eq!(staleness.watermark, 3);
+        assert_eq!(seen.unwrap(), staleness.member_log_id);
+        if staleness.member_id == mocks[1].id {
+            assert_eq!(staleness.gap(), 2);
+        } else {
+            assert_eq!(staleness.member_id, mocks[0].id);
+            assert_eq!(staleness.gap(), 0);
+        }
+    }
+    // queries go round robin, both members answered once
+    for mock in &mocks {
+        assert_eq!(mock.queries.load(Ordering::Relaxed), 1);
+    }
+}
+
+struct CapturingLogger {
+    records: Arc<Mutex<Vec<(LogLevel, String)>>>,
+}
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= LogLevel::Warn
+    }
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            self.records
+                .lock()
+                .push((record.level(), format!("{}", record.args())));
+        }
+    }
+}
+
+#[test]
+fn leader_switch_warning() {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let logger = CapturingLogger {
+        records: records.clone(),
+    };
+    // the logger is process wide and can only be set once, another one may already be there
+    let captured = log::set_logger(|max| {
+        max.set(LogLevelFilter::Warn);
+        Box::new(logger)
+    })
+    .is_ok();
+    let mocks = mock::cluster(&[2371, 2372]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    mocks[0].down.store(true, Ordering::Relaxed);
+    for mock in &mocks {
+        mock.leader_id.store(mocks[1].id, Ordering::Relaxed);
+    }
+    client.execute(SM_ID, commands::put::new(&1)).wait().unwrap().unwrap();
+    assert_eq!(client.leader_id(), mocks[1].id);
+    if !captured {
+        return;
+    }
+    let switch = format!("Switch leader from {}", mocks[0].id);
+    // other tests may add records of their own
+    assert!(records
+        .lock()
+        .iter()
+        .any(|&(level, ref msg)| level == LogLevel::Warn && msg.contains(&switch)));
+}
+
+#[test]
+fn retry_backoff() {
+    let mocks = mock::cluster(&[2373]);
+    mocks[0].on_command(|_: &MockRaft, _| ClientCmdResponse::NotCommitted);
+    let exhaust = |base_backoff: Duration| -> Duration {
+        let mut config = RaftClientConfig::default();
+        config.base_backoff = base_backoff;
+        config.max_backoff = Duration::from_secs(1);
+        let client =
+            RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+        let start = Instant::now();
+        match client.execute(SM_ID, commands::put::new(&1)).wait() {
+            Err(ExecError::ExhaustedRetries { attempts }) => assert_eq!(attempts.len(), 5),
+            other => panic!("{:?}", other),
+        }
+        start.elapsed()
+    };
+    let immediate = exhaust(Duration::from_millis(0));
+    // four retries waiting at least 25, 50, 100 and 200ms
+    let backed_off = exhaust(Duration::from_millis(50));
+    assert!(backed_off >= Duration::from_millis(375));
+    assert!(backed_off > immediate);
+}
+
+#[test]
+fn left_behind_escalation() {
+    let mocks = mock::cluster(&[2374, 2375, 2376, 2377]);
+    let mut config = RaftClientConfig::default();
+    config.left_behind_escalation = Some(2);
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    for i in 0..3 {
+        client.execute(SM_ID, commands::put::new(&i)).wait().unwrap().unwrap();
+    }
+    for mock in &mocks[1..] {
+        mock.last_log_id.store(0, Ordering::Relaxed);
+    }
+    let follower_queries = |mocks: &Vec<Arc<MockRaft>>| -> usize {
+        mocks[1..]
+            .iter()
+            .map(|m| m.queries.load(Ordering::Relaxed))
+            .sum()
+    };
+    for i in 0..4 {
+        let followers_before = follower_queries(&mocks);
+        let res = client.execute(SM_ID, commands::get::new()).wait();
+        assert_eq!(res.unwrap().unwrap(), 3);
+        // without escalation a query starting after the leader asks all three followers
+        assert!(follower_queries(&mocks) - followers_before <= 2);
+        assert_eq!(mocks[0].queries.load(Ordering::Relaxed), i + 1);
+    }
+}
+
+#[test]
+fn rpc_and_operation_timeout() {
+    let mocks = mock::cluster(&[2378, 2379]);
+    mocks[0].on_command(|mock: &MockRaft, entry| {
+        thread::sleep(Duration::from_secs(5));
+        mock.default_command(entry)
+    });
+    let mut config = RaftClientConfig::default();
+    config.rpc_timeout = Some(Duration::from_millis(200));
+    config.operation_timeout = Some(Duration::from_secs(1));
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let start = Instant::now();
+    match client.execute(SM_ID, commands::put::new(&1)).wait() {
+        Err(ExecError::Timeout) => {}
+        other => panic!("{:?}", other),
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+    // the stalled leader timed out and the command was tried elsewhere
+    assert!(mocks[1].commands.load(Ordering::Relaxed) >= 1);
+}
+
+#[test]
+fn query_error_classes() {
+    let mocks = mock::cluster(&[2380]);
+    let client = RaftClient::new(&mock::addrs(&mocks), DEFAULT_SERVICE_ID).unwrap();
+    // members that keep falling behind exhaust the retries
+    mocks[0].on_query(|_: &MockRaft, _| ClientQryResponse::LeftBehind);
+    match client.execute(SM_ID, commands::get::new()).wait() {
+        Err(ExecError::ExhaustedRetries { attempts }) => {
+            assert!(attempts.iter().all(|r| *r == RetryReason::LeftBehind))
+        }
+        other => panic!("{:?}", other),
+    }
+    // the service itself refuses the query
+    mocks[0].down.store(true, Ordering::Relaxed);
+    match client.execute(SM_ID, commands::get::new()).wait() {
+        Err(ExecError::ServerError) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn query_rpc_timeout_error() {
+    let mocks = mock::cluster(&[2381]);
+    mocks[0].on_query(|mock: &MockRaft, entry| {
+        thread::sleep(Duration::from_secs(5));
+        mock.default_query(entry)
+    });
+    let mut config = RaftClientConfig::default();
+    config.rpc_timeout = Some(Duration::from_millis(200));
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    match client.execute(SM_ID, commands::get::new()).wait() {
+        Err(ExecError::Timeout) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn query_rpc_timeout_retried() {
+    let mocks = mock::cluster(&[2388, 2389]);
+    mocks[0].on_query(|mock: &MockRaft, entry| {
+        thread::sleep(Duration::from_secs(2));
+        mock.default_query(entry)
+    });
+    let mut config = RaftClientConfig::default();
+    config.rpc_timeout = Some(Duration::from_millis(200));
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let start = Instant::now();
+    // queries go round robin, the one sent to the stalled member is answered by the other
+    for _ in 0..2 {
+        assert_eq!(client.execute(SM_ID, commands::get::new()).wait().unwrap(), Ok(0));
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(mocks[0].queries.load(Ordering::Relaxed) >= 1);
+    assert_eq!(mocks[1].queries.load(Ordering::Relaxed), 2);
+    // only the leader answers a linearizable query
+    match client
+        .execute_with_consistency(SM_ID, commands::get::new(), ReadConsistency::Linearizable)
+        .wait()
+    {
+        Err(ExecError::Timeout) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn rpc_timeout_every_call() {
+    let mocks = mock::cluster(&[2387]);
+    mocks[0].on_query(|mock: &MockRaft, entry| {
+        thread::sleep(Duration::from_secs(2));
+        mock.default_query(entry)
+    });
+    mocks[0].on_command(|mock: &MockRaft, entry| {
+        thread::sleep(Duration::from_secs(2));
+        mock.default_command(entry)
+    });
+    let mut config = RaftClientConfig::default();
+    config.rpc_timeout = Some(Duration::from_millis(200));
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let start = Instant::now();
+    assert!(client.query_read_index(SM_ID, commands::get::new()).wait().is_err());
+    assert!(client.query_quorum_confirmed(SM_ID, commands::get::new(), 1).wait().is_err());
+    assert!(client.query_detailed(SM_ID, commands::get::new()).wait().is_err());
+    let session = client.session("timeouts");
+    assert!(session.execute(SM_ID, commands::get::new()).wait().is_err());
+    client.pin_leader(mocks[0].id);
+    assert!(client.execute(SM_ID, commands::put::new(&1)).wait().is_err());
+    // every call gave up on its own rpc instead of waiting for the slow member
+    assert!(start.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn query_transport_error() {
+    // a member that is listening without a raft service fails on the rpc layer
+    let bare_addr = String::from("127.0.0.1:2383");
+    let bare = rpc::Server::new(&bare_addr);
+    rpc::Server::listen_and_resume(&bare);
+    let addr = String::from("127.0.0.1:2382");
+    let members = vec![(hash_str(&addr), addr.clone()), (hash_str(&bare_addr), bare_addr)];
+    let mock = mock::start(&addr, &members, members[0].0);
+    thread::sleep(Duration::from_millis(1000));
+    let client = RaftClient::new(&vec![mock.address.clone()], DEFAULT_SERVICE_ID).unwrap();
+    let errors: Vec<_> = (0..2)
+        .map(|_| {
+            client
+                .execute_with_consistency(SM_ID, commands::get::new(), ReadConsistency::Stale)
+                .wait()
+        })
+        .filter_map(|res| res.err())
+        .collect();
+    assert_eq!(errors.len(), 1);
+    match errors[0] {
+        ExecError::Rpc(ref cause) => assert!(cause.contains("ServiceIdNotFound")),
+        ref other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn in_flight_limit() {
+    let mocks = mock::cluster(&[2384]);
+    mocks[0].on_command(|mock: &MockRaft, entry| {
+        thread::sleep(Duration::from_millis(500));
+        mock.default_command(entry)
+    });
+    let mut config = RaftClientConfig::default();
+    config.max_in_flight = Some(1);
+    let client =
+        RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config.clone()).unwrap();
+    let slow_client = client.clone();
+    let slow = thread::spawn(move || slow_client.execute(SM_ID, commands::put::new(&1)).wait());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.in_flight_count(), 1);
+    match client.execute(SM_ID, commands::get::new()).wait() {
+        Err(ExecError::Overloaded) => {}
+        other => panic!("{:?}", other),
+    }
+    match client.query_read_index(SM_ID, commands::get::new()).wait() {
+        Err(ExecError::Overloaded) => {}
+        other => panic!("{:?}", other),
+    }
+    assert!(slow.join().unwrap().unwrap().is_ok());
+    assert_eq!(client.in_flight_count(), 0);
+
+    // with room in the queue the call waits for the slot instead
+    config.max_queued = 1;
+    let client =
+        RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config.clone()).unwrap();
+    let slow_client = client.clone();
+    let slow = thread::spawn(move || slow_client.execute(SM_ID, commands::put::new(&2)).wait());
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    assert!(client.execute(SM_ID, commands::get::new()).wait().unwrap().is_ok());
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(slow.join().unwrap().unwrap().is_ok());
+    assert_eq!(client.in_flight_count(), 0);
+
+    // waiting in the queue counts toward the operation timeout, the slot of a call
+    // that gave up is passed on
+    config.operation_timeout = Some(Duration::from_millis(300));
+    let client = RaftClient::with_config(&mock::addrs(&mocks), DEFAULT_SERVICE_ID, config).unwrap();
+    let slow_client = client.clone();
+    let slow = thread::spawn(move || slow_client.execute(SM_ID, commands::put::new(&3)).wait());
+    thread::sleep(Duration::from_millis(100));
+    match client.execute(SM_ID, commands::get::new()).wait() {
+        Err(ExecError::Timeout) => {}
+        other => panic!("{:?}", other),
+    }
+    assert!(slow.join().unwrap().unwrap().is_ok());
+    assert_eq!(client.in_flight_count(), 0);
+}
@@ -0,0 +1,262 @@
+use bifrost::raft::codec::{BincodeCodec, Codec, Decoding};
+use bifrost::raft::state_machine::master::ExecResult;
+use bifrost::raft::*;
+use bifrost::rpc::Server;
+use bifrost::utils::bincode::serialize;
+use bifrost::utils::time::get_time;
+use bifrost_hasher::hash_str;
+use erased_serde;
+use futures::prelude::*;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// messages used by client tests, the state machine itself is never registered
+raft_state_machine! {
+    def cmd put(v: u64) -> u64;
+    def qry get() -> u64;
+}
+
+/// Bincode with the bytes reversed, counting what went through it
+pub struct ReversingCodec {
+    pub encoded: AtomicUsize,
+    pub decoded: AtomicUsize,
+}
+
+impl ReversingCodec {
+    pub fn new() -> ReversingCodec {
+        ReversingCodec {
+            encoded: AtomicUsize::new(0),
+            decoded: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Codec for ReversingCodec {
+    fn encode(&self, value: &erased_serde::Serialize) -> Vec<u8> {
+        self.encoded.fetch_add(1, Ordering::Relaxed);
+        let mut data = BincodeCodec.encode(value);
+        data.reverse();
+        data
+    }
+    fn decode(&self, data: &[u8], decoding: Decoding) -> Result<(), String> {
+        self.decoded.fetch_add(1, Ordering::Relaxed);
+        let mut data = data.to_vec();
+        data.reverse();
+        BincodeCodec.decode(&data, decoding)
+    }
+}
+
+pub type CmdHandler = Box<Fn(&MockRaft, LogEntry) -> ClientCmdResponse + Send + Sync>;
+pub type QryHandler = Box<Fn(&MockRaft, LogEntry) -> ClientQryResponse + Send + Sync>;
+
+/// A raft service that speaks the client protocol only.
+/// Commands and queries fall back to a trivial leader/follower behaviour unless a handler is set.
+pub struct MockRaft {
+    pub id: u64,
+    pub address: String,
+    pub leader_id: AtomicU64,
+    pub term: AtomicU64,
+    pub last_log_id: AtomicU64,
+    pub members: RwLock<Vec<(u64, String)>>,
+    pub down: AtomicBool, // fail every client request
+    pub commands: AtomicUsize,
+    pub queries: AtomicUsize,
+    pub infos: AtomicUsize,
+    pub cmd_handler: RwLock<Option<CmdHandler>>,
+    pub qry_handler: RwLock<Option<QryHandler>>,
+    pub clock_offset: AtomicI64, // ms added to the timestamp reported in cluster info
+    pub applied_tokens: RwLock<HashMap<u64, u64>>, // idempotency token -> log id applied at
+    pub uncommitted: AtomicU64, // logs at the tail not committed yet
+}
+
+pub fn reply(v: u64) -> ExecResult {
+    Ok(serialize(&Ok::<u64, ()>(v)))
+}
+
+impl MockRaft {
+    pub fn new(address: &String) -> Arc<MockRaft> {
+        Arc::new(MockRaft {
+            id: hash_str(address),
+            address: address.clone(),
+            leader_id: AtomicU64::new(0),
+            term: AtomicU64::new(1),
+            last_log_id: AtomicU64::new(0),
+            members: RwLock::new(Vec::new()),
+            down: AtomicBool::new(false),
+            commands: AtomicUsize::new(0),
+            queries: AtomicUsize::new(0),
+            infos: AtomicUsize::new(0),
+            cmd_handler: RwLock::new(None),
+            qry_handler: RwLock::new(None),
+            clock_offset: AtomicI64::new(0),
+            applied_tokens: RwLock::new(HashMap::new()),
+            uncommitted: AtomicU64::new(0),
+        })
+    }
+    pub fn is_leader(&self) -> bool {
+        self.leader_id.load(Ordering::Relaxed) == self.id
+    }
+    pub fn on_command<F>(&self, f: F)
+    where
+        F: Fn(&MockRaft, LogEntry) -> ClientCmdResponse + Send + Sync + 'static,
+    {
+        *self.cmd_handler.write() = Some(Box::new(f));
+    }
+    pub fn on_query<F>(&self, f: F)
+    where
+        F: Fn(&MockRaft, LogEntry) -> ClientQryResponse + Send + Sync + 'static,
+    {
+        *self.qry_handler.write() = Some(Box::new(f));
+    }
+    pub fn default_command(&self, entry: LogEntry) -> ClientCmdResponse {
+        if !self.is_leader() {
+            return ClientCmdResponse::NotLeader(self.leader_id.load(Ordering::Relaxed));
+        }
+        let term = self.term.load(Ordering::Relaxed);
+        if entry.fence_term.map_or(false, |fence_term| fence_term != term) {
+            return ClientCmdResponse::TermChanged(term);
+        }
+        let mut applied_tokens = self.applied_tokens.write();
+        let applied = entry
+            .idempotency_token
+            .and_then(|token| applied_tokens.get(&token).cloned());
+        let log_id = match applied {
+            Some(log_id) => log_id,
+            None => self.last_log_id.fetch_add(1, Ordering::Relaxed) + 1,
+        };
+        if let Some(token) = entry.idempotency_token {
+            applied_tokens.insert(token, log_id);
+        }
+        ClientCmdResponse::Success {
+            data: reply(log_id),
+            last_log_id: log_id,
+            last_log_term: self.term.load(Ordering::Relaxed),
+        }
+    }
+    pub fn default_query(&self, entry: LogEntry) -> ClientQryResponse {
+        let last_log_id = self.last_log_id.load(Ordering::Relaxed);
+        if entry.id > last_log_id {
+            return ClientQryResponse::LeftBehind;
+        }
+        ClientQryResponse::Success {
+            data: reply(last_log_id),
+            last_log_id,
+            last_log_term: self.term.load(Ordering::Relaxed),
+        }
+    }
+    pub fn cluster_info(&self) -> ClientClusterInfo {
+        ClientClusterInfo {
+            members: self.members.read().clone(),
+            last_log_id: self.last_log_id.load(Ordering::Relaxed),
+            last_log_term: self.term.load(Ordering::Relaxed),
+            leader_id: self.leader_id.load(Ordering::Relaxed),
+            timestamp: get_time() + self.clock_offset.load(Ordering::Relaxed),
+            commit_index: self.last_log_id.load(Ordering::Relaxed)
+                - self.uncommitted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Service for MockRaft {
+    fn append_entries(
+        &self,
+        term: u64,
+        _leader_id: u64,
+        _prev_log_id: u64,
+        _prev_log_term: u64,
+        _entries: Option<Vec<LogEntry>>,
+        _leader_commit: u64,
+    ) -> Box<Future<Item = (u64, AppendEntriesResult), Error = ()>> {
+        box future::finished((term, AppendEntriesResult::Ok))
+    }
+    fn request_vote(
+        &self,
+        term: u64,
+        _candidate_id: u64,
+        _last_log_id: u64,
+        _last_log_term: u64,
+    ) -> Box<Future<Item = ((u64, u64), bool), Error = ()>> {
+        box future::finished(((term, 0), false))
+    }
+    fn install_snapshot(
+        &self,
+        term: u64,
+        _leader_id: u64,
+        _last_included_index: u64,
+        _last_included_term: u64,
+        _data: Vec<u8>,
+    ) -> Box<Future<Item = u64, Error = ()>> {
+        box future::finished(term)
+    }
+    fn c_command(&self, entry: LogEntry) -> Box<Future<Item = ClientCmdResponse, Error = ()>> {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        if self.down.load(Ordering::Relaxed) {
+            return box future::failed(());
+        }
+        let res = match *self.cmd_handler.read() {
+            Some(ref handler) => handler(self, entry),
+            None => self.default_command(entry),
+        };
+        box future::finished(res)
+    }
+    fn c_query(&self, entry: LogEntry) -> Box<Future<Item = ClientQryResponse, Error = ()>> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if self.down.load(Ordering::Relaxed) {
+            return box future::failed(());
+        }
+        let res = match *self.qry_handler.read() {
+            Some(ref handler) => handler(self, entry),
+            None => self.default_query(entry),
+        };
+        box future::finished(res)
+    }
+    fn c_server_cluster_info(&self) -> Box<Future<Item = ClientClusterInfo, Error = ()>> {
+        self.infos.fetch_add(1, Ordering::Relaxed);
+        if self.down.load(Ordering::Relaxed) {
+            return box future::failed(());
+        }
+        box future::finished(self.cluster_info())
+    }
+    fn c_put_offline(&self) -> Box<Future<Item = bool, Error = ()>> {
+        box future::finished(true)
+    }
+}
+dispatch_rpc_service_functions!(MockRaft);
+
+/// Start one mock per port, all knowing each other, with the first one as leader
+pub fn cluster(ports: &[u16]) -> Vec<Arc<MockRaft>> {
+    let addrs: Vec<String> = ports
+        .iter()
+        .map(|port| format!("127.0.0.1:{}", port))
+        .collect();
+    let members: Vec<(u64, String)> = addrs
+        .iter()
+        .map(|addr| (hash_str(addr), addr.clone()))
+        .collect();
+    let leader_id = members[0].0;
+    let mocks: Vec<Arc<MockRaft>> = addrs
+        .iter()
+        .map(|addr| start(addr, &members, leader_id))
+        .collect();
+    thread::sleep(Duration::from_millis(1000));
+    mocks
+}
+
+/// Start a single mock serving at `addr`
+pub fn start(addr: &String, members: &Vec<(u64, String)>, leader_id: u64) -> Arc<MockRaft> {
+    let mock = MockRaft::new(addr);
+    *mock.members.write() = members.clone();
+    mock.leader_id.store(leader_id, Ordering::Relaxed);
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &mock);
+    Server::listen_and_resume(&server);
+    mock
+}
+
+pub fn addrs(mocks: &Vec<Arc<MockRaft>>) -> Vec<String> {
+    mocks.iter().map(|mock| mock.address.clone()).collect()
+}
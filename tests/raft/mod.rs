@@ -1,6 +1,8 @@
 use std::{thread, time};
 
 mod callback;
+mod client;
+mod mock;
 mod primary;
 
 pub fn wait() {
@@ -1,8 +1,14 @@
 use super::wait;
-use bifrost::raft::state_machine::master::ExecError;
+use bifrost::raft::codec::{BincodeCodec, Codec};
+use bifrost::raft::state_machine::master::{ExecError, MasterStateMachine};
+use bifrost::raft::state_machine::StateMachineCtl;
 use bifrost::raft::*;
 use bifrost::rpc::Server;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::commands::{add_and_get, get};
+use bifrost::utils::bincode::deserialize;
 use std::fs::File;
+use std::sync::Arc;
 
 #[test]
 fn startup() {
@@ -169,3 +175,52 @@ fn log_replication() {
     assert_eq!(service4.leader_id(), service1.id);
     assert_eq!(service5.leader_id(), service1.id);
 }
+
+#[test]
+fn dedup_survives_snapshot() {
+    let codec: Arc<Codec> = Arc::new(BincodeCodec);
+    let entry = {
+        let (fn_id, _, data) = add_and_get::new(&3).encode(&*codec);
+        LogEntry {
+            id: 1,
+            term: 1,
+            sm_id: 2,
+            fn_id,
+            data,
+            trace_context: None,
+            fence_term: None,
+            idempotency_token: Some(42),
+        }
+    };
+    let get_entry = {
+        let (fn_id, _, data) = get::new().encode(&*codec);
+        LogEntry {
+            id: 1,
+            term: 1,
+            sm_id: 2,
+            fn_id,
+            data,
+            trace_context: None,
+            fence_term: None,
+            idempotency_token: None,
+        }
+    };
+    let num = |master: &MasterStateMachine| -> u32 {
+        let res: Result<u32, ()> = deserialize(&master.exec_qry(&get_entry).unwrap());
+        res.unwrap()
+    };
+    let mut master = MasterStateMachine::new(DEFAULT_SERVICE_ID, codec.clone());
+    master.register(Box::new(U32::Number::new(2, 0)));
+    master.commit_cmd(&entry).unwrap();
+    assert_eq!(num(&master), 3);
+    let snapshot = master.snapshot().unwrap();
+
+    // a member restored from the snapshot skips the hedged repeat like the others
+    let mut restored = MasterStateMachine::new(DEFAULT_SERVICE_ID, codec.clone());
+    restored.recover(snapshot);
+    restored.register(Box::new(U32::Number::new(2, 0)));
+    assert_eq!(num(&restored), 3);
+    let res: Result<u32, ()> = deserialize(&restored.commit_cmd(&entry).unwrap());
+    assert_eq!(res, Ok(3));
+    assert_eq!(num(&restored), 3);
+}
@@ -3,6 +3,7 @@
 #![feature(proc_macro)]
 #![feature(box_syntax)]
 #![feature(conservative_impl_trait)]
+#![feature(integer_atomics)]
 
 #[macro_use]
 extern crate bifrost;
@@ -10,6 +11,7 @@ extern crate bifrost_hasher;
 extern crate bifrost_plugins;
 extern crate bincode;
 extern crate byteorder;
+extern crate erased_serde;
 extern crate futures_await as futures;
 #[macro_use]
 extern crate serde_derive;
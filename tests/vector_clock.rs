@@ -1,4 +1,19 @@
-use bifrost::vector_clock::StandardVectorClock;
+use bifrost::utils::bincode::{deserialize, serialize};
+use bifrost::vector_clock::{
+    ancestors_of, frontier, relation_matrix, ClockComparator, ClockError, DecodeError,
+    DecodePolicy, MaxMerge, MergePolicy, Relation, ServerVectorClock, StandardVectorClock,
+    VectorClock, VectorClockEpoched, VectorClockWithProvenance, FORMAT_VERSION,
+};
+use bifrost_hasher::hash_str;
+use std::cmp::{max, min, Ordering};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+
+fn clock(pairs: Vec<(u64, u64)>) -> StandardVectorClock {
+    StandardVectorClock::from_pairs_checked(pairs).unwrap()
+}
 
 #[test]
 fn test() {
@@ -10,3 +25,1056 @@ fn test() {
     assert!(blank_clock < clock);
     assert!(blank_clock != clock);
 }
+
+#[test]
+fn from_pairs_checked() {
+    let clock = StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5)]).unwrap();
+    let mut expected = StandardVectorClock::new();
+    for _ in 0..3 {
+        expected.inc(1);
+    }
+    for _ in 0..5 {
+        expected.inc(2);
+    }
+    assert_eq!(clock, expected);
+    assert_eq!(
+        StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5), (1, 4)]),
+        Err(ClockError::DuplicateServer)
+    );
+}
+
+#[test]
+fn try_inc() {
+    let allowed: BTreeSet<u64> = vec![1, 2].into_iter().collect();
+    let mut clock = StandardVectorClock::new();
+    assert_eq!(clock.try_inc(1, &allowed), Ok(1));
+    assert_eq!(clock.try_inc(1, &allowed), Ok(2));
+    assert_eq!(clock.try_inc(2, &allowed), Ok(1));
+    assert_eq!(
+        clock,
+        StandardVectorClock::from_pairs_checked(vec![(1, 2), (2, 1)]).unwrap()
+    );
+}
+
+#[test]
+fn try_inc_unknown_server() {
+    let allowed: BTreeSet<u64> = vec![1, 2].into_iter().collect();
+    let mut clock = StandardVectorClock::from_pairs_checked(vec![(1, 2)]).unwrap();
+    let before = clock.clone();
+    assert_eq!(clock.try_inc(3, &allowed), Err(ClockError::UnknownServer));
+    let nobody = BTreeSet::new();
+    assert_eq!(clock.try_inc(1, &nobody), Err(ClockError::UnknownServer));
+    // no entry is added for the rejected server
+    assert_eq!(clock.len(), 1);
+    assert_eq!(clock, before);
+}
+
+#[test]
+fn self_merge() {
+    let mut clock = StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5)]).unwrap();
+    let snapshot = clock.clone();
+    clock.merge_with(&snapshot);
+    assert_eq!(clock, snapshot);
+    assert_eq!(format!("{:?}", clock), format!("{:?}", snapshot));
+}
+
+#[test]
+fn comparator() {
+    let incoming = StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5)]).unwrap();
+    let others = vec![
+        StandardVectorClock::new(),
+        incoming.clone(),
+        StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 6)]).unwrap(),
+        StandardVectorClock::from_pairs_checked(vec![(1, 2)]).unwrap(),
+        StandardVectorClock::from_pairs_checked(vec![(1, 4), (2, 1)]).unwrap(),
+        StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5), (3, 1)]).unwrap(),
+        StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5), (3, 0)]).unwrap(),
+    ];
+    let comparator = ClockComparator::new(&incoming);
+    // zeros are dropped when normalizing
+    let with_zero = StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5), (4, 0)]).unwrap();
+    let zero_comparator = ClockComparator::new(&with_zero);
+    for other in &others {
+        assert_eq!(comparator.relation_to(other), incoming.relation(other));
+        assert_eq!(zero_comparator.relation_to(other), with_zero.relation(other));
+    }
+}
+
+#[test]
+fn tag() {
+    let clock =
+        StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 500), (u64::max_value(), 77)])
+            .unwrap();
+    let tag = clock.to_tag();
+    assert!(tag
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    let decoded = StandardVectorClock::from_tag(&tag).unwrap();
+    assert_eq!(decoded, clock);
+    assert_eq!(
+        StandardVectorClock::from_tag(&StandardVectorClock::new().to_tag()).unwrap(),
+        StandardVectorClock::new()
+    );
+    assert_eq!(
+        StandardVectorClock::from_tag("a+b/"),
+        Err(DecodeError::InvalidTag)
+    );
+}
+
+#[test]
+fn siblings() {
+    let common = StandardVectorClock::from_pairs_checked(vec![(1, 2), (2, 2)]).unwrap();
+    let mut left = common.clone();
+    left.inc(1);
+    let mut right = common.clone();
+    right.inc(2);
+    assert!(left.is_sibling_of(&right, &common));
+    assert!(right.is_sibling_of(&left, &common));
+    // not concurrent
+    let mut descendant = left.clone();
+    descendant.inc(1);
+    assert!(!descendant.is_sibling_of(&left, &common));
+    // concurrent but not descending from the common ancestor
+    let stranger = StandardVectorClock::from_pairs_checked(vec![(1, 5)]).unwrap();
+    assert!(!stranger.is_sibling_of(&right, &common));
+}
+
+#[test]
+fn wide_counter() {
+    let mut clock: VectorClock<u64, u128> = VectorClock::new();
+    let blank_clock: VectorClock<u64, u128> = VectorClock::new();
+    let mut far_ahead: VectorClock<u64, u128> =
+        VectorClock::from_pairs_checked(vec![(1, u64::max_value() as u128 + 10)]).unwrap();
+    clock.inc(1);
+    assert!(clock > blank_clock);
+    assert!(far_ahead > clock);
+    far_ahead.inc(2);
+    clock.merge_with(&far_ahead);
+    assert_eq!(clock, far_ahead);
+    assert_eq!(clock.relation(&blank_clock), Relation::After);
+}
+
+#[test]
+fn merge_bounded_by_recency() {
+    let mut clock = StandardVectorClock::from_pairs_checked(vec![(1, 100), (2, 5)]).unwrap();
+    let other = StandardVectorClock::from_pairs_checked(vec![(3, 1), (4, 50)]).unwrap();
+    // server 3 has the lowest counter but is the most recently active
+    let last_active = |server: &u64| match *server {
+        1 => 10,
+        2 => 40,
+        3 => 90,
+        _ => 20,
+    };
+    let dropped = clock.merge_bounded_by(&other, 2, last_active);
+    assert_eq!(dropped, vec![(4, 50), (1, 100)]);
+    assert_eq!(
+        clock,
+        StandardVectorClock::from_pairs_checked(vec![(2, 5), (3, 1)]).unwrap()
+    );
+}
+
+#[test]
+fn snapshot_delta() {
+    let server = ServerVectorClock::new(&String::from("127.0.0.1:1234"));
+    server.inc();
+    server.merge_with(&StandardVectorClock::from_pairs_checked(vec![(1, 2)]).unwrap());
+    let first = server.to_clock();
+    server.inc();
+    server.inc();
+    server.merge_with(&StandardVectorClock::from_pairs_checked(vec![(1, 5), (2, 1)]).unwrap());
+    let (second, mut deltas) = server.snapshot_delta(&first);
+    assert_eq!(second, server.to_clock());
+    deltas.sort();
+    let own_id = hash_str(&String::from("127.0.0.1:1234"));
+    let mut expected = vec![(1, 3), (2, 1), (own_id, 2)];
+    expected.sort();
+    assert_eq!(deltas, expected);
+    let (_, no_deltas) = server.snapshot_delta(&second);
+    assert!(no_deltas.is_empty());
+}
+
+#[test]
+fn ancestors() {
+    let target = clock(vec![(1, 3), (2, 2)]);
+    let candidates = vec![
+        clock(vec![(1, 1)]), // ancestor
+        clock(vec![(1, 4), (2, 2)]), // descendant
+        clock(vec![(1, 3), (2, 1)]), // ancestor
+        clock(vec![(3, 1)]), // concurrent
+        clock(vec![(1, 3), (2, 2)]), // equal
+        clock(vec![(1, 1), (2, 5)]), // concurrent
+    ];
+    let ancestors = ancestors_of(&target, &candidates);
+    assert_eq!(ancestors, vec![&candidates[0], &candidates[2]]);
+    assert!(ancestors_of(&target, &[]).is_empty());
+}
+
+#[test]
+fn pairwise_relation_matrix() {
+    use bifrost::vector_clock::Relation::{After, Before, Concurrent, Equal};
+    let clocks = vec![
+        clock(vec![(1, 1)]),
+        clock(vec![(1, 2), (2, 1)]),
+        clock(vec![(2, 3)]),
+        clock(vec![(1, 1)]),
+    ];
+    let matrix = relation_matrix(&clocks);
+    assert_eq!(
+        matrix,
+        vec![
+            vec![Equal, Before, Concurrent, Equal],
+            vec![After, Equal, Concurrent, After],
+            vec![Concurrent, Concurrent, Equal, Concurrent],
+            vec![Equal, Before, Concurrent, Equal],
+        ]
+    );
+    // the lower half is derived, it still has to agree with a direct comparison
+    for i in 0..clocks.len() {
+        for j in 0..clocks.len() {
+            assert_eq!(matrix[i][j], clocks[i].relation(&clocks[j]));
+        }
+    }
+    assert!(relation_matrix::<u64, u64>(&[]).is_empty());
+}
+
+#[test]
+fn causal_frontier() {
+    let clocks = vec![
+        clock(vec![(1, 1)]),
+        clock(vec![(1, 3), (2, 1)]),
+        clock(vec![(1, 2), (2, 1)]),
+        clock(vec![(2, 4)]),
+        clock(vec![(1, 3), (2, 1)]),
+        clock(vec![(3, 1)]),
+    ];
+    assert_eq!(
+        frontier(&clocks),
+        vec![
+            clock(vec![(1, 3), (2, 1)]),
+            clock(vec![(2, 4)]),
+            clock(vec![(3, 1)]),
+        ]
+    );
+    assert!(frontier::<u64, u64>(&[]).is_empty());
+    // one dominated clock and two concurrent maxima
+    let siblings = vec![
+        clock(vec![(1, 2), (2, 1)]),
+        clock(vec![(1, 1)]),
+        clock(vec![(1, 1), (2, 2)]),
+    ];
+    assert_eq!(
+        frontier(&siblings),
+        vec![siblings[0].clone(), siblings[2].clone()]
+    );
+}
+
+#[test]
+fn join_operators() {
+    let a = StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 1)]).unwrap();
+    let b = StandardVectorClock::from_pairs_checked(vec![(2, 4), (3, 2)]).unwrap();
+    let mut merged = a.clone();
+    merged.merge_with(&b);
+    assert_eq!(&a | &b, merged);
+    assert_eq!(&b | &a, merged);
+    let mut joined = a.clone();
+    joined |= &b;
+    assert_eq!(joined, merged);
+    assert!(a.happened_before(&joined) && b.happened_before(&joined));
+}
+
+#[test]
+fn compare_component() {
+    let a = StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 1), (4, 0)]).unwrap();
+    let b = StandardVectorClock::from_pairs_checked(vec![(1, 2), (2, 5), (3, 1)]).unwrap();
+    assert_eq!(a.compare_component(&b, &1), Ordering::Greater);
+    assert_eq!(a.compare_component(&b, &2), Ordering::Less);
+    assert_eq!(a.compare_component(&b, &3), Ordering::Less);
+    assert_eq!(b.compare_component(&a, &3), Ordering::Greater);
+    assert_eq!(a.compare_component(&b, &4), Ordering::Equal);
+    assert_eq!(a.compare_component(&b, &5), Ordering::Equal);
+    assert_eq!(a.compare_component(&a, &1), Ordering::Equal);
+}
+
+#[test]
+fn ordered_with() {
+    let a = StandardVectorClock::from_pairs_checked(vec![(1, 1)]).unwrap();
+    let b = StandardVectorClock::from_pairs_checked(vec![(1, 2), (2, 1)]).unwrap();
+    let c = StandardVectorClock::from_pairs_checked(vec![(3, 1)]).unwrap();
+    assert!(a.is_ordered_with(&b));
+    assert!(b.is_ordered_with(&a));
+    assert!(a.is_ordered_with(&a.clone()));
+    assert!(!a.is_ordered_with(&c));
+    assert!(!c.is_ordered_with(&b));
+}
+
+#[test]
+fn gc_below_frontier() {
+    let mut local = clock(vec![(1, 3), (2, 5), (3, 2), (4, 1)]);
+    let replicas = vec![
+        clock(vec![(1, 4), (2, 5), (3, 1), (4, 1)]),
+        clock(vec![(1, 3), (2, 6), (3, 2)]),
+    ];
+    // server 3 is behind on the first replica, server 4 is unknown to the second
+    assert_eq!(local.gc_below_frontier(&replicas), 2);
+    assert_eq!(local, clock(vec![(3, 2), (4, 1)]));
+    assert_eq!(local.gc_below_frontier(&[]), 0);
+    assert_eq!(local.gc_below_frontier(&replicas), 0);
+}
+
+#[test]
+fn project() {
+    let a = clock(vec![(1, 2), (2, 1), (3, 5), (4, 1)]);
+    let b = clock(vec![(1, 3), (2, 1), (3, 1), (5, 2)]);
+    let dc: BTreeSet<u64> = vec![1, 2].into_iter().collect();
+    assert_eq!(a.project(&dc), clock(vec![(1, 2), (2, 1)]));
+    assert_eq!(b.project(&dc), clock(vec![(1, 3), (2, 1)]));
+    // concurrent overall, ordered within the datacenter
+    assert_eq!(a.relation(&b), Relation::Concurrent);
+    assert_eq!(a.project(&dc).relation(&b.project(&dc)), Relation::Before);
+    assert_eq!(a.project(&BTreeSet::new()), StandardVectorClock::new());
+}
+
+#[test]
+fn merge_audit() {
+    let address = String::from("127.0.0.1:1234");
+    let own_id = hash_str(&address);
+    let server = ServerVectorClock::with_merge_audit(&address, 2);
+    server.inc();
+    server.merge_with(&clock(vec![(1, 1)]));
+    server.merge_with(&clock(vec![(1, 3), (2, 1)]));
+    server.merge_with(&clock(vec![(2, 2)]));
+    let audit = server.merge_audit();
+    // only the last two merges are kept
+    assert_eq!(audit.len(), 2);
+    assert_eq!(audit[0].before, clock(vec![(own_id, 1), (1, 1)]));
+    assert_eq!(audit[0].incoming, clock(vec![(1, 3), (2, 1)]));
+    assert_eq!(audit[0].after, clock(vec![(own_id, 1), (1, 3), (2, 1)]));
+    assert_eq!(audit[1].before, audit[0].after);
+    assert_eq!(audit[1].after, clock(vec![(own_id, 1), (1, 3), (2, 2)]));
+    assert_eq!(audit[1].after, server.to_clock());
+
+    let plain = ServerVectorClock::new(&address);
+    plain.merge_with(&clock(vec![(1, 1)]));
+    assert!(plain.merge_audit().is_empty());
+}
+
+#[test]
+fn relation_strict() {
+    // (a, b, relation, relation_strict)
+    let cases = vec![
+        (vec![], vec![], Relation::Equal, Relation::Equal),
+        (vec![(1, 1)], vec![(1, 1)], Relation::Equal, Relation::Equal),
+        (vec![(1, 1)], vec![], Relation::After, Relation::After),
+        (vec![], vec![(1, 1)], Relation::Before, Relation::Before),
+        (vec![(1, 1)], vec![(1, 2)], Relation::Before, Relation::Before),
+        (vec![(1, 1)], vec![(2, 1)], Relation::Concurrent, Relation::Concurrent),
+        // explicit zero against an absent server
+        (vec![(1, 0)], vec![], Relation::Equal, Relation::After),
+        (vec![], vec![(1, 0)], Relation::Equal, Relation::Before),
+        (vec![(1, 0)], vec![(1, 0)], Relation::Equal, Relation::Equal),
+        (vec![(1, 0), (2, 1)], vec![(2, 1)], Relation::Equal, Relation::After),
+        (vec![(1, 0)], vec![(2, 0)], Relation::Equal, Relation::Concurrent),
+        (vec![(1, 0)], vec![(1, 1)], Relation::Before, Relation::Before),
+        (vec![(1, 0), (2, 2)], vec![(2, 1), (3, 1)], Relation::Concurrent, Relation::Concurrent),
+    ];
+    for (a, b, relation, strict) in cases {
+        let (a, b) = (clock(a), clock(b));
+        assert_eq!(a.relation(&b), relation, "{:?} {:?}", a, b);
+        assert_eq!(a.relation_strict(&b), strict, "{:?} {:?}", a, b);
+    }
+}
+
+#[test]
+fn sum() {
+    let clocks = vec![
+        clock(vec![(1, 3)]),
+        clock(vec![(1, 1), (2, 4)]),
+        clock(vec![(2, 2), (3, 1)]),
+    ];
+    let mut folded = StandardVectorClock::new();
+    for c in clocks.iter() {
+        folded.merge_with(c);
+    }
+    assert_eq!(clocks.into_iter().sum::<StandardVectorClock>(), folded);
+    assert_eq!(folded, clock(vec![(1, 3), (2, 4), (3, 1)]));
+    let empty: Vec<StandardVectorClock> = Vec::new();
+    assert_eq!(empty.into_iter().sum::<StandardVectorClock>(), StandardVectorClock::new());
+}
+
+#[test]
+fn truncate_to_top_n() {
+    let mut c = clock(vec![(1, 4), (2, 9), (3, 4), (4, 1), (5, 7)]);
+    let dropped = c.truncate_to_top_n(3);
+    // servers 1 and 3 tie, the lower id stays
+    assert_eq!(dropped, vec![(3, 4), (4, 1)]);
+    assert_eq!(c, clock(vec![(1, 4), (2, 9), (5, 7)]));
+    assert!(c.truncate_to_top_n(3).is_empty());
+    assert_eq!(c.truncate_to_top_n(0).len(), 3);
+    assert_eq!(c, StandardVectorClock::new());
+}
+
+#[test]
+fn align_with() {
+    let a = clock(vec![(1, 2), (3, 1)]);
+    let b = clock(vec![(2, 5), (3, 4)]);
+    let (aligned_a, aligned_b) = a.align_with(&b);
+    assert_eq!(aligned_a, clock(vec![(1, 2), (2, 0), (3, 1)]));
+    assert_eq!(aligned_b, clock(vec![(1, 0), (2, 5), (3, 4)]));
+    assert_eq!(aligned_a.len(), 3);
+    // explicit zeros do not change equality
+    assert!(aligned_a.equals(&a));
+    assert_eq!(aligned_a.relation(&aligned_b), Relation::Concurrent);
+    let (same_a, same_b) = aligned_a.align_with(&aligned_b);
+    assert_eq!(same_a, aligned_a);
+    assert_eq!(same_b, aligned_b);
+}
+
+#[test]
+fn not_after() {
+    let a = clock(vec![(1, 1)]);
+    let b = clock(vec![(1, 2)]);
+    let c = clock(vec![(2, 1)]);
+    assert_eq!(a.relation(&b), Relation::Before);
+    assert!(a.not_after(&b));
+    assert!(a.not_after(&a.clone()));
+    assert_eq!(a.relation(&c), Relation::Concurrent);
+    assert!(a.not_after(&c));
+    assert_eq!(b.relation(&a), Relation::After);
+    assert!(!b.not_after(&a));
+}
+
+#[test]
+fn versioned_bytes() {
+    let clock = StandardVectorClock::from_pairs_checked(vec![(1, 3), (300, 2)]).unwrap();
+    let data = clock.to_bytes();
+    assert_eq!(data[0], FORMAT_VERSION);
+    // v1: entry count, then (server, counter) varints
+    let v1 = vec![1, 2, 1, 3, 0xac, 0x02, 2];
+    assert_eq!(data, v1);
+    assert_eq!(StandardVectorClock::from_bytes(&v1).unwrap(), clock);
+    assert!(StandardVectorClock::supported_versions().contains(&1));
+
+    let mut unknown = v1.clone();
+    unknown[0] = 200;
+    assert_eq!(
+        StandardVectorClock::from_bytes(&unknown),
+        Err(DecodeError::UnsupportedVersion(200))
+    );
+    assert_eq!(
+        StandardVectorClock::from_bytes(&[]),
+        Err(DecodeError::Truncated)
+    );
+}
+
+#[test]
+fn merge_with_policy() {
+    // server 1 is trusted, server 2 may advance by at most 5 per merge, server 3 is frozen
+    struct Capped;
+    impl MergePolicy<u64> for Capped {
+        fn merge_counter(&self, server: &u64, mine: u64, theirs: u64) -> u64 {
+            match *server {
+                1 => max(mine, theirs),
+                2 => max(mine, min(theirs, mine + 5)),
+                _ => mine,
+            }
+        }
+    }
+    let incoming = clock(vec![(1, 100), (2, 100), (3, 100)]);
+    let mut local = clock(vec![(1, 1), (2, 1)]);
+    local.merge_with_policy(&incoming, &Capped);
+    assert_eq!(local, clock(vec![(1, 100), (2, 6)]));
+    // no zero entry for server 3
+    assert_eq!(local.len(), 2);
+
+    let mut default = clock(vec![(1, 1), (2, 1)]);
+    default.merge_with_policy(&incoming, &MaxMerge);
+    let mut merged = clock(vec![(1, 1), (2, 1)]);
+    merged.merge_with(&incoming);
+    assert_eq!(default, merged);
+}
+
+#[test]
+fn hybrid_estimate() {
+    let mut phys = vec![0u64, 1_000, 1_000, 990];
+    let mut clock = StandardVectorClock::new();
+    clock.inc(1);
+    clock.inc(2);
+    let mut last = clock.hybrid_estimate(|server| phys[*server as usize]);
+    assert_eq!(last, 1_000 << 16 | 1);
+    for step in 0..200 {
+        clock.inc(step % 3 + 1);
+        if step % 50 == 0 {
+            phys[3] += 20;
+        }
+        let estimate = clock.hybrid_estimate(|server| phys[*server as usize]);
+        assert!(estimate >= last);
+        last = estimate;
+    }
+    assert_eq!(last >> 16, 1_070);
+    assert_eq!(StandardVectorClock::new().hybrid_estimate(|_| 5), 0);
+}
+
+#[test]
+fn provenance() {
+    let (source_a, source_b) = (100, 200);
+    let mut tracked = VectorClockWithProvenance::new();
+    tracked.merge_with(&clock(vec![(1, 5), (2, 1), (3, 2)]), source_a);
+    tracked.merge_with(&clock(vec![(1, 3), (2, 4), (3, 2), (4, 1)]), source_b);
+    assert_eq!(tracked.provenance(&1), Some(source_a));
+    assert_eq!(tracked.provenance(&2), Some(source_b));
+    // a tie does not take over the position
+    assert_eq!(tracked.provenance(&3), Some(source_a));
+    assert_eq!(tracked.provenance(&4), Some(source_b));
+    assert_eq!(tracked.provenance(&5), None);
+    assert_eq!(
+        *tracked.clock(),
+        clock(vec![(1, 5), (2, 4), (3, 2), (4, 1)])
+    );
+}
+
+#[test]
+fn prune() {
+    let live: BTreeSet<u64> = vec![1, 2].into_iter().collect();
+    // concurrent on the live servers, server 9 is gone
+    let mut a = clock(vec![(1, 3), (2, 1), (9, 4)]);
+    let mut b = clock(vec![(1, 1), (2, 2), (9, 7)]);
+    assert_eq!(a.relation(&b), Relation::Concurrent);
+    assert_eq!(a.prune(&live), 1);
+    assert_eq!(b.prune(&live), 1);
+    assert_eq!(a.relation(&b), Relation::Concurrent);
+    assert_eq!(b.relation(&a), Relation::Concurrent);
+    assert_eq!(a, clock(vec![(1, 3), (2, 1)]));
+    assert_eq!(a.prune(&live), 0);
+
+    let server = ServerVectorClock::new(&String::from("127.0.0.1:1"));
+    server.merge_with(&clock(vec![(1, 1), (7, 2), (8, 3)]));
+    assert_eq!(server.prune(&live), 2);
+    assert_eq!(server.to_clock(), clock(vec![(1, 1)]));
+}
+
+#[test]
+fn iter_nonzero() {
+    let clock = StandardVectorClock::from_pairs_checked(vec![(3, 2), (1, 0), (2, 5)]).unwrap();
+    let entries: Vec<(u64, u64)> = clock.iter_nonzero().map(|(s, c)| (*s, *c)).collect();
+    assert_eq!(entries, vec![(2, 5), (3, 2)]);
+    // the explicit zero is still in the clock, equal to one without it
+    assert_eq!(clock.len(), 3);
+    assert_eq!(
+        clock.relation(&StandardVectorClock::from_pairs_checked(vec![(3, 2), (2, 5)]).unwrap()),
+        Relation::Equal
+    );
+}
+
+#[test]
+fn iter() {
+    let mut clock = StandardVectorClock::new();
+    assert!(clock.is_empty());
+    assert_eq!(clock.iter().count(), 0);
+    clock.inc(30);
+    clock.inc(10);
+    clock.inc(10);
+    clock.learn_from(&StandardVectorClock::from_pairs_checked(vec![(20, 0)]).unwrap());
+    let entries: Vec<(u64, u64)> = clock.iter().map(|(s, c)| (*s, *c)).collect();
+    assert_eq!(entries, vec![(10, 2), (20, 0), (30, 1)]);
+    assert_eq!(clock.len(), 3);
+    assert!(!clock.is_empty());
+}
+
+#[test]
+fn inc_saturates() {
+    let clock_at = |counter| StandardVectorClock::from_pairs_checked(vec![(1, counter)]).unwrap();
+    let mut clock = clock_at(u64::max_value() - 1);
+    let before = clock.clone();
+    assert_eq!(clock.inc(1), clock_at(u64::max_value()));
+    assert_eq!(clock.inc(1), clock_at(u64::max_value()));
+    // no wrap around, the clock is still after the one it advanced from
+    assert_eq!(clock.relation(&before), Relation::After);
+}
+
+#[test]
+fn relation_matches_definition() {
+    // every clock over three servers with counters absent, 0, 1 or 2
+    let clocks: Vec<StandardVectorClock> = (0..64)
+        .map(|n: u64| {
+            let pairs = (0..3)
+                .filter_map(|server| match n >> (server * 2) & 3 {
+                    0 => None,
+                    counter => Some((server, counter - 1)),
+                })
+                .collect();
+            StandardVectorClock::from_pairs_checked(pairs).unwrap()
+        })
+        .collect();
+    for a in &clocks {
+        for b in &clocks {
+            let expected = if a.equals(b) {
+                Relation::Equal
+            } else if a.happened_before(b) {
+                Relation::Before
+            } else if b.happened_before(a) {
+                Relation::After
+            } else {
+                Relation::Concurrent
+            };
+            assert_eq!(a.relation(b), expected, "{:?} {:?}", a, b);
+        }
+    }
+}
+
+#[test]
+fn join() {
+    let clocks = vec![
+        clock(vec![(1, 4), (2, 1)]),
+        clock(vec![(1, 1), (2, 3)]),
+        clock(vec![(2, 2), (3, 5)]),
+    ];
+    let joined = StandardVectorClock::join(&clocks);
+    assert_eq!(joined, clock(vec![(1, 4), (2, 3), (3, 5)]));
+    for input in &clocks {
+        assert!(joined >= *input);
+        assert_eq!(input.relation(&joined), Relation::Before);
+    }
+    assert_eq!(StandardVectorClock::join(&[]), StandardVectorClock::new());
+
+    let server = ServerVectorClock::new(&String::from("127.0.0.1:1"));
+    server.merge_with(&clock(vec![(3, 7)]));
+    server.join_into(&clocks);
+    assert_eq!(server.to_clock(), clock(vec![(1, 4), (2, 3), (3, 7)]));
+}
+
+#[test]
+fn hash_ignores_zero_entries() {
+    fn hash_of(clock: &StandardVectorClock) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        clock.hash(&mut hasher);
+        hasher.finish()
+    }
+    let mut clock = StandardVectorClock::new();
+    clock.inc(1);
+    let original = hash_of(&clock);
+    // a server learned with a zero counter is as good as absent
+    clock.merge_with(&StandardVectorClock::from_pairs_checked(vec![(2, 0)]).unwrap());
+    assert_eq!(clock.len(), 1);
+    assert_eq!(hash_of(&clock), original);
+
+    let mut explicit = StandardVectorClock::from_pairs_checked(vec![(1, 1), (2, 0)]).unwrap();
+    // equal clocks hash the same
+    assert_eq!(explicit, clock);
+    assert_eq!(hash_of(&explicit), original);
+    assert_eq!(explicit.normalize(), 1);
+    assert_eq!(explicit, clock);
+    assert_eq!(hash_of(&explicit), original);
+}
+
+#[test]
+fn dominates() {
+    let base = clock(vec![(1, 2), (2, 1)]);
+    let equal = base.clone();
+    let after = clock(vec![(1, 3), (2, 1)]);
+    let before = clock(vec![(1, 1)]);
+    let concurrent = clock(vec![(1, 1), (2, 4)]);
+    assert!(base.dominates(&equal));
+    assert!(!base.strictly_dominates(&equal));
+    assert!(base.dominates(&before));
+    assert!(base.strictly_dominates(&before));
+    assert!(!base.dominates(&after));
+    assert!(!base.strictly_dominates(&after));
+    assert!(after.strictly_dominates(&base));
+    assert!(!base.dominates(&concurrent));
+    assert!(!concurrent.dominates(&base));
+    assert!(!base.strictly_dominates(&concurrent));
+}
+
+#[test]
+fn etag() {
+    let mut tagged = clock(vec![(1, 2), (2, 1)]);
+    let tag = tagged.etag();
+    assert_eq!(tag, tagged.etag());
+    assert_eq!(tag, clock(vec![(2, 1), (1, 2)]).etag());
+    assert_eq!(tag, clock(vec![(1, 2), (2, 1), (3, 0)]).etag());
+    // same counter sum, different shape
+    assert_ne!(tag, clock(vec![(1, 1), (2, 2)]).etag());
+    assert_ne!(tag, clock(vec![(1, 3)]).etag());
+    let mut seen = vec![tag];
+    for server in vec![1, 2, 3, 1] {
+        let next = tagged.inc(server).etag();
+        assert!(!seen.contains(&next));
+        seen.push(next);
+    }
+}
+
+#[test]
+fn counter_types() {
+    let mut small: VectorClock<u64, u32> = VectorClock::new();
+    small.inc(1);
+    small.inc(1);
+    let mut other: VectorClock<u64, u32> = VectorClock::from_pairs_checked(vec![(2, 3)]).unwrap();
+    assert_eq!(small.relation(&other), Relation::Concurrent);
+    other.merge_with(&small);
+    assert!(other.dominates(&small));
+    let mut full: VectorClock<u64, u8> = VectorClock::from_pairs_checked(vec![(1, 255)]).unwrap();
+    let saturated = full.clone();
+    assert_eq!(full.inc(1), saturated);
+    let mut tracked: VectorClockWithProvenance<u64, u16> = VectorClockWithProvenance::new();
+    tracked.merge_with(&VectorClock::from_pairs_checked(vec![(1, 7)]).unwrap(), 9);
+    assert_eq!(tracked.provenance(&1), Some(9));
+}
+
+#[test]
+fn merge_all_from_bytes() {
+    let mut corrupt = clock(vec![(4, 9)]).to_bytes();
+    corrupt.push(0);
+    let batch = vec![
+        clock(vec![(1, 2)]).to_bytes(),
+        corrupt,
+        clock(vec![(1, 1)]).to_bytes(), // already covered
+        clock(vec![(2, 3)]).to_bytes(),
+    ];
+
+    let mut skipping = clock(vec![(1, 1)]);
+    assert_eq!(
+        skipping.merge_all_from_bytes(batch.clone(), DecodePolicy::Skip),
+        Ok(2)
+    );
+    assert_eq!(skipping, clock(vec![(1, 2), (2, 3)]));
+
+    let mut failing = clock(vec![(1, 1)]);
+    assert_eq!(
+        failing.merge_all_from_bytes(batch, DecodePolicy::Fail),
+        Err(DecodeError::TrailingBytes)
+    );
+    assert_eq!(failing, clock(vec![(1, 1)]));
+}
+
+#[test]
+fn delta_since() {
+    let local = clock(vec![(1, 5), (2, 2), (3, 1), (4, 0)]);
+    let peer = clock(vec![(1, 3), (2, 2), (3, 4), (5, 1)]);
+    let delta = local.delta_since(&peer);
+    assert_eq!(delta, clock(vec![(1, 5)]));
+    let mut reconstructed = peer.clone();
+    reconstructed.merge_with(&delta);
+    assert_eq!(
+        reconstructed,
+        StandardVectorClock::join(&[local.clone(), peer.clone()])
+    );
+    assert!(local.delta_since(&local).is_empty());
+    assert_eq!(local.delta_since(&StandardVectorClock::new()).len(), 3);
+}
+
+#[test]
+fn merge_with_changed() {
+    let mut local = clock(vec![(1, 2), (2, 1)]);
+    assert!(!local.merge_with_changed(&clock(vec![(1, 2), (2, 1)])));
+    assert!(!local.merge_with_changed(&clock(vec![(1, 1), (3, 0)])));
+    assert!(local.merge_with_changed(&clock(vec![(1, 3), (2, 1)])));
+    assert_eq!(local, clock(vec![(1, 3), (2, 1)]));
+    assert!(local.merge_with_changed(&clock(vec![(4, 1)])));
+}
+
+#[test]
+fn diff_count() {
+    let a = clock(vec![(1, 2), (2, 2), (3, 1), (4, 0)]);
+    let b = clock(vec![(1, 2), (2, 5), (5, 1), (6, 0)]);
+    // servers 2, 3 and 5
+    assert_eq!(a.diff_count(&b), 3);
+    assert_eq!(b.diff_count(&a), 3);
+    assert_eq!(a.diff_count(&a), 0);
+    assert_eq!(a.diff_count(&StandardVectorClock::new()), 3);
+}
+
+#[test]
+fn server_id() {
+    let address = String::from("127.0.0.1:1");
+    assert_eq!(
+        ServerVectorClock::new(&address).server_id(),
+        hash_str(&address)
+    );
+    let server = ServerVectorClock::from_id(7);
+    assert_eq!(server.server_id(), 7);
+    assert_eq!(
+        server.inc(),
+        StandardVectorClock::from_pairs_checked(vec![(7, 1)]).unwrap()
+    );
+}
+
+#[test]
+fn restore() {
+    let persisted = StandardVectorClock::from_pairs_checked(vec![(1, 4), (7, 2)]).unwrap();
+    let server = ServerVectorClock::from_id(7);
+    server.restore(persisted.clone());
+    assert_eq!(server.to_clock(), persisted);
+    // counting goes on from the restored value
+    assert_eq!(
+        server.inc(),
+        StandardVectorClock::from_pairs_checked(vec![(1, 4), (7, 3)]).unwrap()
+    );
+}
+
+#[test]
+fn merge_reporting() {
+    let cases = vec![
+        (clock(vec![(1, 2)]), clock(vec![(1, 3), (3, 1)])),
+        (clock(vec![(1, 2), (2, 1)]), clock(vec![(1, 1), (2, 3)])),
+        (clock(vec![(1, 2), (2, 1)]), clock(vec![(1, 1)])),
+        (clock(vec![(1, 2)]), clock(vec![(1, 2), (2, 0)])),
+    ];
+    for (local, incoming) in cases {
+        let mut merged = local.clone();
+        let (relation, advanced) = merged.merge_reporting(&incoming);
+        assert_eq!(relation, local.relation(&incoming));
+        assert_eq!(merged, &local | &incoming);
+        // the servers where the incoming clock was ahead
+        let expected: Vec<u64> = incoming
+            .delta_since(&local)
+            .iter()
+            .map(|(server, _)| *server)
+            .collect();
+        assert_eq!(advanced, expected);
+    }
+}
+
+#[test]
+fn default() {
+    assert!(VectorClock::<u64>::default().is_empty());
+    let server = ServerVectorClock::default();
+    assert_eq!(server.server_id(), 0);
+    assert!(server.to_clock().is_empty());
+}
+
+#[test]
+fn from_iterator() {
+    let clock = StandardVectorClock::from_iter(vec![(1, 3), (2, 5), (1, 4), (2, 1)]);
+    assert_eq!(
+        clock,
+        StandardVectorClock::from_pairs_checked(vec![(1, 4), (2, 5)]).unwrap()
+    );
+    let collected: StandardVectorClock = vec![(3, 1), (3, 2)].into_iter().collect();
+    assert_eq!(collected.iter().collect::<Vec<_>>(), vec![(&3, &2)]);
+}
+
+#[test]
+fn extend() {
+    let mut clock = StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5)]).unwrap();
+    clock.extend(vec![(1, 2), (2, 6), (3, 1), (3, 4)]);
+    assert_eq!(
+        clock,
+        StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 6), (3, 4)]).unwrap()
+    );
+    // same as merge_with, counters never go back
+    let mut merged = StandardVectorClock::from_pairs_checked(vec![(1, 3), (2, 5)]).unwrap();
+    let incoming = StandardVectorClock::from_iter(vec![(1, 2), (2, 6), (3, 4)]);
+    merged.merge_with(&incoming);
+    assert_eq!(clock, merged);
+}
+
+#[test]
+fn chain_from() {
+    let server = ServerVectorClock::from_id(1);
+    let mut entries = Vec::new();
+    for _ in 0..4 {
+        entries.push(server.inc());
+    }
+    let chain = |entries: &Vec<StandardVectorClock>| -> Vec<u64> {
+        let mut prev_hash = 0;
+        entries
+            .iter()
+            .map(|clock| {
+                prev_hash = clock.chain_from(prev_hash);
+                prev_hash
+            })
+            .collect()
+    };
+    let stored = chain(&entries);
+    assert_eq!(chain(&entries), stored);
+    let mut tampered = entries.clone();
+    tampered[1] = StandardVectorClock::from_pairs_checked(vec![(1, 2), (2, 1)]).unwrap();
+    let recomputed = chain(&tampered);
+    assert_eq!(recomputed[0], stored[0]);
+    assert!(recomputed[1..]
+        .iter()
+        .zip(stored[1..].iter())
+        .all(|(a, b)| a != b));
+    let mut reordered = entries.clone();
+    reordered.swap(1, 2);
+    assert_ne!(chain(&reordered)[2], stored[2]);
+}
+
+#[test]
+fn concurrent_total_order() {
+    let a = clock(vec![(1, 2), (2, 1)]);
+    let b = clock(vec![(1, 1), (2, 2)]);
+    assert_eq!(a.relation(&b), Relation::Concurrent);
+    assert_ne!(a.cmp(&b), Ordering::Equal);
+    assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+    assert_eq!(a.partial_cmp(&b), Some(a.cmp(&b)));
+    let mut set = BTreeSet::new();
+    set.insert(a.clone());
+    set.insert(b.clone());
+    assert_eq!(set.len(), 2);
+    // causal order is kept, the joined clock comes after both
+    let joined = &a | &b;
+    set.insert(joined.clone());
+    set.insert(clock(vec![(1, 1)]));
+    let ordered: Vec<StandardVectorClock> = set.into_iter().collect();
+    assert_eq!(ordered.first(), Some(&clock(vec![(1, 1)])));
+    assert_eq!(ordered.last(), Some(&joined));
+}
+
+#[test]
+fn total_order_near_counter_max() {
+    let max = u64::max_value();
+    // the counters of each sum past the largest u64
+    let before = StandardVectorClock::from_iter(vec![(1, max), (2, 1)]);
+    let after = StandardVectorClock::from_iter(vec![(1, max), (2, 2)]);
+    let concurrent = StandardVectorClock::from_iter(vec![(1, max - 1), (2, 5)]);
+    assert_eq!(before.relation(&after), Relation::Before);
+    assert_eq!(before.relation(&concurrent), Relation::Concurrent);
+    assert_eq!(after.relation(&concurrent), Relation::Concurrent);
+    assert_eq!(before.cmp(&after), Ordering::Less);
+    assert_eq!(concurrent.cmp(&before), Ordering::Less);
+    assert_eq!(concurrent.cmp(&after), Ordering::Less);
+    let mut clocks = vec![after.clone(), concurrent.clone(), before.clone()];
+    clocks.sort();
+    assert_eq!(clocks, vec![concurrent, before, after]);
+}
+
+#[test]
+fn inc_by() {
+    let batched = ServerVectorClock::from_id(1);
+    let single = ServerVectorClock::from_id(1);
+    for _ in 0..3 {
+        single.inc();
+    }
+    assert_eq!(batched.inc_by(3), single.to_clock());
+    assert_eq!(
+        batched.inc_by(2),
+        StandardVectorClock::from_iter(vec![(1, 5)])
+    );
+}
+
+#[test]
+fn merge_saturating_sum() {
+    let mut clock = StandardVectorClock::from_iter(vec![(1, 2), (2, u64::max_value() - 1)]);
+    let added = StandardVectorClock::from_iter(vec![(1, 3), (2, 5), (3, 1)]);
+    clock.merge_saturating_sum(&added);
+    assert_eq!(
+        clock,
+        StandardVectorClock::from_iter(vec![(1, 5), (2, u64::max_value()), (3, 1)])
+    );
+    clock.merge_saturating_sum(&StandardVectorClock::from_iter(vec![(2, u64::max_value())]));
+    assert_eq!(clock.iter().nth(1), Some((&2, &u64::max_value())));
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactClock {
+    #[serde(with = "bifrost::vector_clock::compact")]
+    clock: StandardVectorClock,
+}
+
+#[test]
+fn compact_serde() {
+    let clock = StandardVectorClock::from_iter(vec![(3, 1), (1, 4), (2, 2)]);
+    let compact = serialize(&CompactClock {
+        clock: clock.clone(),
+    });
+    let decoded: CompactClock = deserialize(&compact);
+    assert_eq!(decoded.clock, clock);
+    // bincode frames a sorted map and a sorted sequence the same way
+    let default = serialize(&clock);
+    assert_eq!(compact, default);
+    let from_default: CompactClock = deserialize(&default);
+    assert_eq!(from_default.clock, clock);
+    let pairs: Vec<(u64, u64)> = deserialize(&compact);
+    assert_eq!(pairs, vec![(1, 4), (2, 2), (3, 1)]);
+}
+
+#[test]
+fn is_extension_of() {
+    let server = ServerVectorClock::from_id(1);
+    let mut log = vec![server.inc(), server.inc()];
+    server.merge_with(&StandardVectorClock::from_iter(vec![(2, 3)]));
+    log.push(server.inc());
+    for step in log.windows(2) {
+        assert!(step[1].is_extension_of(&step[0]));
+    }
+    assert!(log[2].is_extension_of(&log[2]));
+    // a regressing successor and a concurrent one are both invalid
+    assert!(!log[0].is_extension_of(&log[1]));
+    let forked = StandardVectorClock::from_iter(vec![(1, 1), (3, 1)]);
+    assert!(!forked.is_extension_of(&log[1]));
+}
+
+#[test]
+fn relations_against() {
+    let incoming = clock(vec![(1, 2), (2, 2)]);
+    let peers = vec![
+        clock(vec![(1, 1), (2, 2)]),
+        clock(vec![(1, 3), (2, 2)]),
+        clock(vec![(1, 1), (3, 1)]),
+        clock(vec![(1, 2), (2, 2)]),
+    ];
+    let relations = incoming.relations_against(&peers);
+    assert_eq!(
+        relations,
+        vec![
+            Relation::After,
+            Relation::Before,
+            Relation::Concurrent,
+            Relation::Equal,
+        ]
+    );
+    let one_by_one: Vec<Relation> = peers.iter().map(|peer| incoming.relation(peer)).collect();
+    assert_eq!(incoming.relations_against(peers.iter()), one_by_one);
+}
+
+#[test]
+fn inc_in_place() {
+    let mut clock = StandardVectorClock::from_iter(vec![(1, 2)]);
+    let mut cloned = clock.clone();
+    clock.inc_in_place(1);
+    clock.inc_in_place(2);
+    cloned.inc(1);
+    assert_eq!(cloned.inc(2), clock);
+    assert_eq!(clock, StandardVectorClock::from_iter(vec![(1, 3), (2, 1)]));
+    let server = ServerVectorClock::from_id(1);
+    server.inc_in_place();
+    server.inc_in_place();
+    assert_eq!(
+        server.to_clock(),
+        StandardVectorClock::from_iter(vec![(1, 2)])
+    );
+}
+
+#[test]
+fn merge_with_epoch() {
+    let mut clock = StandardVectorClock::from_iter(vec![(1, 2)]);
+    let stale = VectorClockEpoched::new(3, StandardVectorClock::from_iter(vec![(1, 9), (2, 9)]));
+    assert!(!clock.merge_with_epoch(&stale, 4));
+    assert_eq!(clock, StandardVectorClock::from_iter(vec![(1, 2)]));
+    let current = VectorClockEpoched::new(4, StandardVectorClock::from_iter(vec![(2, 1)]));
+    assert!(clock.merge_with_epoch(&current, 4));
+    let newer = VectorClockEpoched::new(5, StandardVectorClock::from_iter(vec![(1, 3)]));
+    assert!(clock.merge_with_epoch(&newer, 4));
+    assert_eq!(clock, StandardVectorClock::from_iter(vec![(1, 3), (2, 1)]));
+}
+
+#[test]
+fn clear() {
+    let mut clock = StandardVectorClock::from_iter(vec![(1, 2), (2, 1)]);
+    clock.clear();
+    assert!(clock.is_empty());
+    assert_eq!(clock, StandardVectorClock::new());
+    clock.inc_in_place(1);
+    assert_eq!(clock, StandardVectorClock::from_iter(vec![(1, 1)]));
+}
+
+#[test]
+fn reset() {
+    let server = ServerVectorClock::from_id(1);
+    server.inc_by(3);
+    server.merge_with(&StandardVectorClock::from_iter(vec![(2, 4)]));
+    server.reset();
+    assert_eq!(server.to_clock(), StandardVectorClock::new());
+    assert_eq!(server.inc(), StandardVectorClock::from_iter(vec![(1, 1)]));
+}